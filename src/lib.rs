@@ -14,17 +14,41 @@
 //! Command::new(&args[0]).args(&args[1..]).status().unwrap();
 //! ```
 
+mod sha1;
+
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::convert::TryFrom;
 use std::ffi::{OsStr, OsString};
 use std::fmt::{self, Display};
-use std::path::PathBuf;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
 
 /// Default SSH port number 22.
 pub const DEFAULT_SSH_PORT: u16 = 22;
 
+/// SSH-based program that a command is generated for.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Transport {
+    /// The `ssh` client.
+    Ssh,
+    /// The `scp` file copy program.
+    Scp,
+    /// The `sftp` file transfer program.
+    Sftp,
+}
+
 /// Host and port number. Can be serialized and deserialized with
-/// serde using the "host[:port]" format.
-#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+/// serde using the "host[:port]" format. In human-readable formats
+/// such as JSON, deserialization also accepts a map with a "host" key
+/// and an optional "port" key; the host may instead be given as
+/// "hostname", as in ssh_config.
+///
+/// Addresses are ordered by host and then by port, with no port
+/// sorting before any explicit port.
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Address {
     /// Host name or IP address.
     pub host: String,
@@ -42,6 +66,23 @@ pub enum AddressError {
     /// The port number could not be parsed as a u16.
     #[error("invalid address port")]
     InvalidPort,
+
+    /// The host starts with "-" (which ssh would interpret as an
+    /// option) or contains whitespace or control characters.
+    #[error("invalid address host")]
+    InvalidHost,
+}
+
+/// Reject hosts that ssh could misinterpret, such as
+/// "-oProxyCommand=...".
+fn check_host(host: &str) -> Result<(), AddressError> {
+    if host.starts_with('-')
+        || host.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        Err(AddressError::InvalidHost)
+    } else {
+        Ok(())
+    }
 }
 
 impl Address {
@@ -60,19 +101,194 @@ impl Address {
             port: None,
         }
     }
+
+    /// Get the port number, or `DEFAULT_SSH_PORT` if no port is set.
+    pub fn effective_port(&self) -> u16 {
+        self.port.unwrap_or(DEFAULT_SSH_PORT)
+    }
+
+    /// Format the address as "host:port", always including the port
+    /// (`DEFAULT_SSH_PORT` if unset). IPv6 hosts are enclosed in
+    /// brackets. Unlike `Display`, the output never omits the port.
+    pub fn host_port(&self) -> String {
+        Address {
+            port: Some(self.effective_port()),
+            ..self.clone()
+        }
+        .to_string()
+    }
+
+    /// Split the address into the host and the effective port
+    /// (`DEFAULT_SSH_PORT` if unset). IPv6 hosts are not bracketed.
+    pub fn into_parts(self) -> (String, u16) {
+        let port = self.effective_port();
+        (self.host, port)
+    }
+
+    /// Get the host and the effective port (`DEFAULT_SSH_PORT` if
+    /// unset). IPv6 hosts are not bracketed.
+    pub fn as_parts(&self) -> (&str, u16) {
+        (&self.host, self.effective_port())
+    }
+
+    /// Get a copy of the address with the host normalized for
+    /// comparison: DNS names are lowercased and a single trailing dot
+    /// is removed. IP addresses, including IPv6 addresses with a zone
+    /// such as "fe80::1%eth0", are left unchanged. The port is not
+    /// changed.
+    pub fn normalized(&self) -> Address {
+        if self.host.contains(':') || self.host.parse::<IpAddr>().is_ok() {
+            return self.clone();
+        }
+        let host = self.host.strip_suffix('.').unwrap_or(&self.host);
+        Address {
+            host: host.to_ascii_lowercase(),
+            port: self.port,
+        }
+    }
+
+    /// Check whether the port is unset or set to `DEFAULT_SSH_PORT`.
+    pub fn is_default_port(&self) -> bool {
+        self.effective_port() == DEFAULT_SSH_PORT
+    }
+
+    /// Get a copy of the address with the port set to
+    /// `DEFAULT_SSH_PORT` if it was not already set.
+    pub fn with_default_port(self) -> Address {
+        Address {
+            port: Some(self.effective_port()),
+            ..self
+        }
+    }
+
+    /// Parse an address in "host[:port]" format, where the port may
+    /// also be a service name such as "ssh". Service names are looked
+    /// up in "/etc/services". If the service is unknown,
+    /// [`AddressError::InvalidPort`] is returned.
+    pub fn parse_service(input: &str) -> Result<Address, AddressError> {
+        match input.parse() {
+            Err(AddressError::InvalidPort) => {
+                // Parsing only fails with InvalidPort if there is a
+                // valid host followed by a colon and the port.
+                let (host, service) =
+                    input.rsplit_once(':').ok_or(AddressError::InvalidPort)?;
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                let services = std::fs::read_to_string("/etc/services")
+                    .map_err(|_| AddressError::InvalidPort)?;
+                let port = find_service_port(&services, service)
+                    .ok_or(AddressError::InvalidPort)?;
+                Ok(Address::new(host, port))
+            }
+            result => result,
+        }
+    }
+
+    /// Parse an address that may have been pasted from a URL, such as
+    /// "ssh://host:22/". An "ssh://" prefix and a trailing slash are
+    /// stripped before parsing the rest in "host[:port]" format.
+    pub fn parse_lenient(input: &str) -> Result<Address, AddressError> {
+        let input = input.strip_prefix("ssh://").unwrap_or(input);
+        let input = input.strip_suffix('/').unwrap_or(input);
+        input.parse()
+    }
+
+    /// Resolve the host to socket addresses, using
+    /// `DEFAULT_SSH_PORT` if no port is set. The host may be a host
+    /// name or a literal IP address.
+    ///
+    /// This performs a blocking DNS lookup. Note that ssh resolves
+    /// the host itself and may see different results, for example if
+    /// "~/.ssh/config" sets a HostName.
+    pub fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        Ok((self.host.as_str(), self.effective_port())
+            .to_socket_addrs()?
+            .collect())
+    }
+
+    /// Create an `ssh-keyscan` command that fetches the host's public
+    /// keys, for example to pre-seed a known-hosts file. The "-p"
+    /// option is only added if the port is set to something other
+    /// than `DEFAULT_SSH_PORT`. IPv6 hosts are passed without
+    /// brackets.
+    pub fn keyscan_command(&self) -> Vec<OsString> {
+        let mut output: Vec<OsString> = vec!["ssh-keyscan".into()];
+        if !self.is_default_port() {
+            output.extend_from_slice(&[
+                "-p".into(),
+                self.effective_port().to_string().into(),
+            ]);
+        }
+        output.push(self.host.clone().into());
+        output
+    }
+
+    /// Get the flag and value for passing this address's port to
+    /// `transport`. Note that ssh uses "-p" while scp and sftp use
+    /// "-P" (for scp, "-p" means "preserve times and modes").
+    ///
+    /// If no port is set, the value is `DEFAULT_SSH_PORT`.
+    pub fn port_flag(&self, transport: Transport) -> (&'static str, String) {
+        let flag = match transport {
+            Transport::Ssh => "-p",
+            Transport::Scp | Transport::Sftp => "-P",
+        };
+        (flag, self.effective_port().to_string())
+    }
+}
+
+/// Find the TCP port for the service `name` in `services`, which is
+/// in the format of "/etc/services". Aliases are also matched.
+fn find_service_port(services: &str, name: &str) -> Option<u16> {
+    services.lines().find_map(|line| {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let service = fields.next()?;
+        let (port, protocol) = fields.next()?.split_once('/')?;
+        if protocol == "tcp" && (service == name || fields.any(|a| a == name)) {
+            port.parse().ok()
+        } else {
+            None
+        }
+    })
 }
 
 impl std::str::FromStr for Address {
     type Err = AddressError;
 
-    /// Parse an address in "host[:port]" format.
+    /// Parse an address in "host[:port]" format. An IPv6 host must be
+    /// enclosed in brackets if a port is given, for example
+    /// "[::1]:2222". The brackets are not included in `host`.
     fn from_str(address: &str) -> Result<Self, Self::Err> {
+        // Bare IPv6 address with no port
+        if address.parse::<Ipv6Addr>().is_ok() {
+            return Ok(Address::from_host(address));
+        }
+
+        // Bracketed IPv6 address with an optional port
+        if let Some(rest) = address.strip_prefix('[') {
+            let (host, rest) =
+                rest.split_once(']').ok_or(AddressError::InvalidFormat)?;
+            if host.is_empty() {
+                return Err(AddressError::InvalidFormat);
+            }
+            check_host(host)?;
+            return match rest.strip_prefix(':') {
+                Some(port) => port
+                    .parse()
+                    .map(|port| Address::new(host, port))
+                    .map_err(|_| AddressError::InvalidPort),
+                None if rest.is_empty() => Ok(Address::from_host(host)),
+                None => Err(AddressError::InvalidFormat),
+            };
+        }
+
         let mut iter = address.split(':');
         if let Some(host) = iter.next() {
             // Reject empty hosts
             if host.is_empty() {
                 return Err(AddressError::InvalidFormat);
             }
+            check_host(host)?;
 
             if let Some(port) = iter.next() {
                 // Reject more than two colons
@@ -95,13 +311,86 @@ impl std::str::FromStr for Address {
     }
 }
 
+impl From<SocketAddr> for Address {
+    /// Create an address from the IP and port of `addr`. IPv6 hosts
+    /// are stored without brackets, as with all addresses. A non-zero
+    /// IPv6 scope id is kept as a "%<scope_id>" zone suffix, since a
+    /// link-local address cannot be reached without it.
+    fn from(addr: SocketAddr) -> Address {
+        let host = match addr {
+            SocketAddr::V6(addr) if addr.scope_id() != 0 => {
+                format!("{}%{}", addr.ip(), addr.scope_id())
+            }
+            _ => addr.ip().to_string(),
+        };
+        Address::new(&host, addr.port())
+    }
+}
+
+impl From<(String, u16)> for Address {
+    fn from((host, port): (String, u16)) -> Address {
+        Address {
+            host,
+            port: Some(port),
+        }
+    }
+}
+
+impl TryFrom<&str> for Address {
+    type Error = AddressError;
+
+    /// Parse an address in "host[:port]" format. This is the same as
+    /// [`str::parse`].
+    fn try_from(address: &str) -> Result<Address, AddressError> {
+        address.parse()
+    }
+}
+
 struct AddressVisitor;
 
 impl<'de> de::Visitor<'de> for AddressVisitor {
     type Value = Address;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("host[:port]")
+        formatter.write_str("host[:port] or a map with host and port")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::MapAccess<'de>,
+    {
+        let mut host: Option<String> = None;
+        let mut port: Option<u16> = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "host" | "hostname" => {
+                    if host.is_some() {
+                        return Err(de::Error::duplicate_field("host"));
+                    }
+                    host = Some(map.next_value()?);
+                }
+                "port" => {
+                    if port.is_some() {
+                        return Err(de::Error::duplicate_field("port"));
+                    }
+                    port = Some(map.next_value()?);
+                }
+                other => {
+                    return Err(de::Error::unknown_field(
+                        other,
+                        &["host", "hostname", "port"],
+                    ));
+                }
+            }
+        }
+        let host = host.ok_or_else(|| de::Error::missing_field("host"))?;
+        if host.is_empty() {
+            return Err(de::Error::custom("invalid address format"));
+        }
+        if check_host(&host).is_err() {
+            return Err(de::Error::custom("invalid address host"));
+        }
+        Ok(Address { host, port })
     }
 
     fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
@@ -116,6 +405,9 @@ impl<'de> de::Visitor<'de> for AddressVisitor {
             Err(AddressError::InvalidPort) => {
                 Err(E::custom("invalid port number"))
             }
+            Err(AddressError::InvalidHost) => {
+                Err(E::custom("invalid address host"))
+            }
         }
     }
 }
@@ -125,7 +417,13 @@ impl<'de> Deserialize<'de> for Address {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_str(AddressVisitor)
+        // Only self-describing formats can say whether a map or a
+        // string follows; others always get the serialized string.
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(AddressVisitor)
+        } else {
+            deserializer.deserialize_str(AddressVisitor)
+        }
     }
 }
 
@@ -139,139 +437,6060 @@ impl Serialize for Address {
 }
 
 impl Display for Address {
+    /// Format the address in "host[:port]" format. IPv6 hosts are
+    /// enclosed in brackets.
+    ///
+    /// The port is included whenever it is set, so that the output
+    /// parses back to the same address. The alternate form (`{:#}`)
+    /// leaves out the port if it is the default (see
+    /// [`Address::is_default_port`]), which is useful for display in
+    /// user interfaces.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let Some(port) = self.port {
-            write!(f, "{}:{}", self.host, port)
+        if self.host.contains(':') {
+            write!(f, "[{}]", self.host)?;
         } else {
-            write!(f, "{}", self.host)
+            write!(f, "{}", self.host)?;
+        }
+        if let Some(port) = self.port {
+            if !(f.alternate() && self.is_default_port()) {
+                write!(f, ":{}", port)?;
+            }
         }
+        Ok(())
     }
 }
 
-/// Inputs for an SSH command, excluding the remote command itself.
+/// OpenSSH client version. Versions compare by major and then minor
+/// number.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct SshVersion {
+    /// Major version number.
+    pub major: u32,
+    /// Minor version number.
+    pub minor: u32,
+}
+
+impl SshVersion {
+    /// Create a new version.
+    pub fn new(major: u32, minor: u32) -> SshVersion {
+        SshVersion { major, minor }
+    }
+
+    /// Parse the output of "ssh -V", for example "OpenSSH_9.6p1
+    /// Ubuntu-3ubuntu13, OpenSSL 3.0.13 30 Jan 2024". Returns `None`
+    /// if there is no "OpenSSH_[...]<major>.<minor>" token.
+    pub fn parse(version_output: &str) -> Option<SshVersion> {
+        let start = version_output.find("OpenSSH_")?;
+        let token = version_output[start..].split(&[' ', ','][..]).next()?;
+        let version = &token[token.find(|c: char| c.is_ascii_digit())?..];
+        let (major, rest) = version.split_once('.')?;
+        let minor_len = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        Some(SshVersion {
+            major: major.parse().ok()?,
+            minor: rest[..minor_len].parse().ok()?,
+        })
+    }
+}
+
+impl Display for SshVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Warning that an option is not supported by an ssh version.
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub struct SshParams {
-    /// Target address.
-    pub address: Address,
+pub struct VersionWarning {
+    /// Name of the ssh option, for example "SetEnv".
+    pub option: &'static str,
+    /// Minimum version that supports the option.
+    pub required: SshVersion,
+}
 
-    /// Optional identity path ("-i" option).
-    pub identity: Option<PathBuf>,
+impl Display for VersionWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "option {} requires OpenSSH {} or newer",
+            self.option, self.required
+        )
+    }
+}
 
-    /// Target user name.
-    pub user: Option<String>,
+/// Detect the version of the OpenSSH client by running "ssh -V".
+///
+/// This blocks until the child process exits.
+pub fn detect_ssh_version() -> io::Result<SshVersion> {
+    let output = Command::new(DEFAULT_PROGRAM).arg("-V").output()?;
+    // ssh prints its version to stderr.
+    let text = String::from_utf8_lossy(&output.stderr);
+    SshVersion::parse(&text).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unrecognized ssh version: {}", text.trim()),
+        )
+    })
+}
 
-    /// If false, skip the known-host check and do not add the target
-    /// to the known-hosts file. This is useful, for example, with
-    /// ephemeral VMs.
-    ///
-    /// Setting this to false adds these flags:
-    /// 1. -oStrictHostKeyChecking=no
-    /// 2. -oUserKnownHostsFile=/dev/null
-    pub strict_host_key_checking: bool,
+/// Get the local user name from the environment.
+fn local_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .unwrap_or_default()
 }
 
-impl Default for SshParams {
-    fn default() -> SshParams {
-        SshParams {
-            address: Address::default(),
-            identity: None,
-            user: None,
-            strict_host_key_checking: true,
+/// Find the home directory of `user` in `passwd`, which is in the
+/// format of "/etc/passwd".
+fn find_passwd_home(passwd: &str, user: &str) -> Option<PathBuf> {
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() >= 6 && fields[0] == user {
+            Some(fields[5].into())
+        } else {
+            None
         }
+    })
+}
+
+/// Expand a leading "~" or "~user" in `path` to the home directory.
+/// The current user's home directory is read from `HOME`, and other
+/// users' from "/etc/passwd". If the home directory cannot be found,
+/// `path` is returned unchanged.
+fn expand_home_path(path: &Path) -> PathBuf {
+    let rest = match path.to_str().and_then(|path| path.strip_prefix('~')) {
+        Some(rest) => rest,
+        None => return path.to_path_buf(),
+    };
+    let (user, tail) = rest.split_once('/').unwrap_or((rest, ""));
+    let home = if user.is_empty() {
+        std::env::var_os("HOME").map(PathBuf::from)
+    } else {
+        std::fs::read_to_string("/etc/passwd")
+            .ok()
+            .and_then(|passwd| find_passwd_home(&passwd, user))
+    };
+    match home {
+        Some(home) if tail.is_empty() => home,
+        Some(home) => home.join(tail),
+        None => path.to_path_buf(),
     }
 }
 
-impl SshParams {
-    /// Create a full SSH command.
-    pub fn command<S: AsRef<OsStr>>(&self, args: &[S]) -> Vec<OsString> {
-        let mut output: Vec<OsString> = Vec::new();
-        output.push("ssh".into());
+/// Get the local host name from `gethostname`, as ssh does for the
+/// "%l" and "%C" tokens. An empty string is returned on failure.
+#[cfg(unix)]
+fn local_hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the pointer and length describe a valid, writable buffer.
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if ret != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
 
-        if !self.strict_host_key_checking {
-            output.extend_from_slice(&[
-                "-oStrictHostKeyChecking=no".into(),
-                "-oUserKnownHostsFile=/dev/null".into(),
-            ]);
+/// Get the local host name from the "COMPUTERNAME" environment
+/// variable. This is a best-effort substitute for `gethostname` and
+/// may not match ssh's "%l" and "%C" tokens.
+#[cfg(not(unix))]
+fn local_hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+/// Quote `value` with double quotes if needed so that ssh's config
+/// parser treats it as a single argument.
+fn config_quote(value: &str) -> String {
+    if value.is_empty()
+        || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\')
+    {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Reverse `config_quote`: strip surrounding double quotes and
+/// unescape backslashes.
+fn config_unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => {
+            let mut output = String::new();
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                if c == '\\' {
+                    output.extend(chars.next());
+                } else {
+                    output.push(c);
+                }
+            }
+            output
+        }
+        None => value.to_string(),
+    }
+}
+
+/// Quote `arg` so that a POSIX shell treats it as a single word.
+fn shell_quote(arg: &OsStr) -> String {
+    let arg = arg.to_string_lossy();
+    let is_safe =
+        |c: char| c.is_ascii_alphanumeric() || "@%+=:,./-_".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.into_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Quote `arg` so that Tcl (and therefore expect) treats it as a
+/// single word, preferring brace quoting.
+fn tcl_quote(arg: &str) -> String {
+    const SPECIAL: &str = "$[]{}\";\\";
+    if !arg.is_empty()
+        && !arg.contains(|c: char| c.is_whitespace() || SPECIAL.contains(c))
+    {
+        return arg.to_string();
+    }
+
+    // Brace quoting works unless the braces are unbalanced or the
+    // word ends with a backslash.
+    let mut depth = 0i32;
+    let balanced = arg.chars().all(|c| {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => {}
         }
-        output.push("-oBatchMode=yes".into());
+        depth >= 0
+    }) && depth == 0;
+    if balanced && !arg.ends_with('\\') {
+        return format!("{{{}}}", arg);
+    }
 
-        if let Some(identity) = &self.identity {
-            output.extend_from_slice(&["-i".into(), identity.into()]);
+    let mut output = String::new();
+    for c in arg.chars() {
+        match c {
+            '\n' => output.push_str("\\n"),
+            c if c.is_whitespace() || SPECIAL.contains(c) => {
+                output.push('\\');
+                output.push(c);
+            }
+            c => output.push(c),
         }
+    }
+    output
+}
 
-        if let Some(port) = self.address.port {
-            output.extend_from_slice(&["-p".into(), port.to_string().into()]);
+/// Quote each of `args` and join them with spaces to form a single
+/// shell command string.
+fn shell_join<S: AsRef<OsStr>>(args: &[S]) -> String {
+    args.iter()
+        .map(|arg| shell_quote(arg.as_ref()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Type of port forwarding.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum ForwardKind {
+    /// Forward a local port to a destination reachable from the
+    /// remote host ("-L" option).
+    Local,
+    /// Forward a remote port to a destination reachable from the
+    /// local host ("-R" option).
+    Remote,
+    /// Dynamic application-level (SOCKS) forwarding on a local port
+    /// ("-D" option).
+    Dynamic,
+}
+
+/// Port forwarding specification.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct Forward {
+    /// Type of forwarding.
+    pub kind: ForwardKind,
+
+    /// Listening side in "[bind_address:]port" format. For remote
+    /// forwards this is on the remote host.
+    pub listen: String,
+
+    /// Destination in "host:hostport" format. Not used for dynamic
+    /// forwards.
+    pub destination: Option<String>,
+}
+
+impl Forward {
+    /// Create a local forward.
+    pub fn local(listen: &str, destination: &str) -> Forward {
+        Forward {
+            kind: ForwardKind::Local,
+            listen: listen.to_string(),
+            destination: Some(destination.to_string()),
+        }
+    }
+
+    /// Create a remote forward.
+    pub fn remote(listen: &str, destination: &str) -> Forward {
+        Forward {
+            kind: ForwardKind::Remote,
+            listen: listen.to_string(),
+            destination: Some(destination.to_string()),
         }
+    }
 
-        let target = if let Some(user) = &self.user {
-            format!("{}@{}", user, self.address.host)
+    /// Create a dynamic forward.
+    pub fn dynamic(listen: &str) -> Forward {
+        Forward {
+            kind: ForwardKind::Dynamic,
+            listen: listen.to_string(),
+            destination: None,
+        }
+    }
+
+    /// Get the arguments for this forward in flag form, for example
+    /// `["-L", "8080:localhost:80"]`.
+    pub fn to_flag_args(&self) -> Vec<OsString> {
+        let flag = match self.kind {
+            ForwardKind::Local => "-L",
+            ForwardKind::Remote => "-R",
+            ForwardKind::Dynamic => "-D",
+        };
+        let spec = if let Some(destination) = &self.destination {
+            format!("{}:{}", self.listen, destination)
         } else {
-            self.address.host.clone()
+            self.listen.clone()
         };
+        vec![flag.into(), spec.into()]
+    }
 
-        output.push(target.into());
-        output.extend(args.iter().map(|arg| arg.into()));
+    /// Get the ssh_config keyword and value for this forward, for
+    /// example `("LocalForward", "8080 localhost:80")`.
+    fn config_entry(&self) -> (&'static str, String) {
+        let name = match self.kind {
+            ForwardKind::Local => "LocalForward",
+            ForwardKind::Remote => "RemoteForward",
+            ForwardKind::Dynamic => "DynamicForward",
+        };
+        let value = if let Some(destination) = &self.destination {
+            format!("{} {}", self.listen, destination)
+        } else {
+            self.listen.clone()
+        };
+        (name, value)
+    }
 
-        output
+    /// Get the argument for this forward in option form, for example
+    /// "-oLocalForward=8080 localhost:80". This matches the syntax
+    /// used in ssh_config.
+    pub fn to_option_arg(&self) -> OsString {
+        let (name, value) = self.config_entry();
+        format!("-o{}={}", name, value).into()
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_test::{assert_tokens, Token};
-    use std::path::Path;
+/// Connection sharing mode ("-oControlMaster" option).
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlMaster {
+    /// Use an existing master connection if there is one, but do not
+    /// become a master.
+    No,
+    /// Become the master connection, listening on the control path.
+    Yes,
+    /// Use an existing master connection, or become the master if
+    /// there is none.
+    Auto,
+    /// Like `Yes`, but ask for confirmation before sharing.
+    Ask,
+    /// Like `Auto`, but ask for confirmation before sharing.
+    Autoask,
+}
 
-    #[test]
-    fn test_address_parse() {
-        assert_eq!("a".parse(), Ok(Address::from_host("a")));
-        assert_eq!("a:1234".parse(), Ok(Address::new("a", 1234)));
-        assert_eq!("".parse::<Address>(), Err(AddressError::InvalidFormat));
-        assert_eq!("a:b".parse::<Address>(), Err(AddressError::InvalidPort));
-        assert_eq!(
-            "a:1234:5678".parse::<Address>(),
-            Err(AddressError::InvalidFormat)
-        );
+impl Display for ControlMaster {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            ControlMaster::No => "no",
+            ControlMaster::Yes => "yes",
+            ControlMaster::Auto => "auto",
+            ControlMaster::Ask => "ask",
+            ControlMaster::Autoask => "autoask",
+        };
+        f.write_str(s)
     }
+}
 
-    #[test]
-    fn test_address_display() {
-        let addr = Address::from_host("abc");
-        assert_eq!(format!("{}", addr), "abc");
-        let addr = Address::new("abc", 123);
-        assert_eq!(format!("{}", addr), "abc:123");
+/// Whether remote forwards may bind to non-loopback addresses on the
+/// remote host ("-oGatewayPorts" option).
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GatewayPorts {
+    /// Forwarded ports only bind to the loopback address.
+    No,
+    /// Forwarded ports bind to all interfaces.
+    Yes,
+    /// The bind address given in each forward is used.
+    ClientSpecified,
+}
+
+impl Display for GatewayPorts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            GatewayPorts::No => "no",
+            GatewayPorts::Yes => "yes",
+            GatewayPorts::ClientSpecified => "clientspecified",
+        };
+        f.write_str(s)
     }
+}
 
-    #[test]
-    fn test_address_tokens() {
-        assert_tokens(&Address::from_host("abc"), &[Token::Str("abc")]);
-        assert_tokens(&Address::new("abc", 123), &[Token::Str("abc:123")]);
+/// How the server's host key is checked against known hosts.
+#[derive(
+    Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum KnownHostsPolicy {
+    /// Use `strict_host_key_checking` and `known_hosts_files`.
+    #[default]
+    Default,
+    /// Accept any host key and do not record it. This is the same as
+    /// setting `strict_host_key_checking` to false.
+    Ignore,
+    /// Accept and record new host keys in this file, which should be
+    /// empty or nonexistent at the start of the session. Unlike
+    /// `Ignore`, a host key that changes during the session is still
+    /// detected. See `SessionKnownHosts` (with the `tempfile`
+    /// feature) for creating a temporary file that is deleted
+    /// afterwards.
+    SessionTemp(PathBuf),
+}
+
+fn is_default_policy(policy: &KnownHostsPolicy) -> bool {
+    *policy == KnownHostsPolicy::Default
+}
+
+/// Temporary known-hosts file for [`KnownHostsPolicy::SessionTemp`].
+/// The file is deleted when this is dropped, so it must outlive any
+/// ssh processes that use it.
+///
+/// Requires the `tempfile` feature.
+#[cfg(feature = "tempfile")]
+#[derive(Debug)]
+pub struct SessionKnownHosts {
+    path: tempfile::TempPath,
+}
+
+#[cfg(feature = "tempfile")]
+impl SessionKnownHosts {
+    /// Create a new empty known-hosts file in the system's temporary
+    /// directory.
+    pub fn new() -> io::Result<SessionKnownHosts> {
+        let file = tempfile::Builder::new()
+            .prefix("nbssh-known-hosts-")
+            .tempfile()?;
+        Ok(SessionKnownHosts {
+            path: file.into_temp_path(),
+        })
     }
 
-    #[test]
-    fn test_command() {
-        let target = SshParams {
-            address: "localhost:9222".parse().unwrap(),
-            identity: Some(Path::new("/myIdentity").to_path_buf()),
-            user: Some("me".to_string()),
-            strict_host_key_checking: false,
+    /// Path of the known-hosts file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get a [`KnownHostsPolicy::SessionTemp`] for this file.
+    pub fn policy(&self) -> KnownHostsPolicy {
+        KnownHostsPolicy::SessionTemp(self.path.to_path_buf())
+    }
+}
+
+/// Agent forwarding mode ("-A", "-a", and "-oForwardAgent" options).
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardAgent {
+    /// Disable agent forwarding ("-a").
+    No,
+    /// Forward the agent at `SSH_AUTH_SOCK` ("-A").
+    Yes,
+    /// Forward the agent listening on this Unix socket
+    /// ("-oForwardAgent=path"). ssh also accepts an environment
+    /// variable name such as "$MY_AGENT_SOCK" here.
+    Socket(PathBuf),
+}
+
+/// IP address family used to connect ("-4" and "-6" options).
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    /// Use either IPv4 or IPv6; ssh's default.
+    #[default]
+    Any,
+    /// Use IPv4 only ("-4").
+    Inet,
+    /// Use IPv6 only ("-6").
+    Inet6,
+}
+
+impl Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            AddressFamily::Any => "any",
+            AddressFamily::Inet => "inet",
+            AddressFamily::Inet6 => "inet6",
         };
-        let cmd = target.command(&["arg1", "arg2"]);
-        assert_eq!(
-            cmd,
-            vec![
-                "ssh",
-                "-oStrictHostKeyChecking=no",
-                "-oUserKnownHostsFile=/dev/null",
-                "-oBatchMode=yes",
-                "-i",
-                "/myIdentity",
-                "-p",
-                "9222",
+        f.write_str(s)
+    }
+}
+
+fn is_any_family(family: &AddressFamily) -> bool {
+    *family == AddressFamily::Any
+}
+
+/// Verbosity of ssh's log output ("-oLogLevel" option).
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum LogLevel {
+    /// No output.
+    Quiet,
+    /// Fatal errors only.
+    Fatal,
+    /// Errors.
+    Error,
+    /// Informational messages; ssh's default.
+    Info,
+    /// Verbose messages.
+    Verbose,
+    /// Debug output, level 1.
+    Debug1,
+    /// Debug output, level 2.
+    Debug2,
+    /// Debug output, level 3.
+    Debug3,
+}
+
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            LogLevel::Quiet => "QUIET",
+            LogLevel::Fatal => "FATAL",
+            LogLevel::Error => "ERROR",
+            LogLevel::Info => "INFO",
+            LogLevel::Verbose => "VERBOSE",
+            LogLevel::Debug1 => "DEBUG1",
+            LogLevel::Debug2 => "DEBUG2",
+            LogLevel::Debug3 => "DEBUG3",
+        };
+        f.write_str(s)
+    }
+}
+
+const DEFAULT_PROGRAM: &str = "ssh";
+
+fn is_default_program(program: &Path) -> bool {
+    program == Path::new(DEFAULT_PROGRAM)
+}
+
+/// Format a bool as an ssh option value.
+fn yes_no(b: bool) -> &'static str {
+    if b {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+/// Convert a duration to whole seconds for an ssh option, rounding up.
+/// ssh treats zero as "disabled", so a sub-second duration must not be
+/// rounded down to zero.
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+fn is_true(b: &bool) -> bool {
+    *b
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+/// Inputs for an SSH command, excluding the remote command itself.
+///
+/// Implements `Hash`, so it can be used as a `HashMap` key, for
+/// example to cache connections.
+///
+/// Can be serialized and deserialized with serde. Fields that are
+/// unset or equal to their default are omitted when serializing, and
+/// missing fields take their default value when deserializing.
+#[derive(Clone, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(default)]
+pub struct SshParams {
+    /// Target address. If the port is set, it is passed with a
+    /// single "-p" option, which takes precedence over any Port in
+    /// ssh_config. No other field emits a port.
+    pub address: Address,
+
+    /// SSH program to run. Defaults to "ssh", which is looked up in
+    /// `PATH`.
+    #[serde(skip_serializing_if = "is_default_program")]
+    pub program: PathBuf,
+
+    /// Optional ssh config file ("-F" option). This is emitted before
+    /// any other option since the config affects them. Use
+    /// "/dev/null" to ignore the user's "~/.ssh/config" entirely,
+    /// which is useful for reproducible automation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_file: Option<PathBuf>,
+
+    /// Patterns of option names that ssh should ignore if it does not
+    /// recognize them ("-oIgnoreUnknown" option), so that options for
+    /// newer clients do not make older clients fail. The patterns are
+    /// joined with commas and emitted before all other "-o" options,
+    /// since IgnoreUnknown only applies to options that follow it.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ignore_unknown: Vec<String>,
+
+    /// Label for this connection ("-oTag" option), which can be
+    /// matched in ssh_config with a "Match tag" block. Requires
+    /// OpenSSH 9.4 or later.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+
+    /// Optional identity path ("-i" option).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<PathBuf>,
+
+    /// Additional identity paths. Each one gets its own "-i" option
+    /// after `identity`, and ssh tries them in order.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extra_identities: Vec<PathBuf>,
+
+    /// Whether to only use the explicitly-listed identities rather
+    /// than also offering keys from the agent. This avoids "too many
+    /// authentication failures" errors when the agent holds many keys.
+    ///
+    /// If `None` (the default), "-oIdentitiesOnly=yes" is added
+    /// automatically when more than one explicit identity is
+    /// provided. `Some(true)` or `Some(false)` always emits the
+    /// corresponding option.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identities_only: Option<bool>,
+
+    /// Agent forwarding. If `None` (the default), ssh's config
+    /// decides. A [`ForwardAgent::Socket`] path is checked by
+    /// [`SshParams::validate`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forward_agent: Option<ForwardAgent>,
+
+    /// Whether to allow password authentication
+    /// ("-oPasswordAuthentication" option).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_authentication: Option<bool>,
+
+    /// Whether to allow keyboard-interactive authentication
+    /// ("-oKbdInteractiveAuthentication" option).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kbd_interactive_authentication: Option<bool>,
+
+    /// Authentication methods to try, in order
+    /// ("-oPreferredAuthentications" option), for example
+    /// "publickey". The methods are joined with commas.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub preferred_authentications: Vec<String>,
+
+    /// Number of password prompts before giving up
+    /// ("-oNumberOfPasswordPrompts" option). ssh's default is 3.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub number_of_password_prompts: Option<u32>,
+
+    /// Target user name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    /// If false, skip the known-host check and do not add the target
+    /// to the known-hosts file. This is useful, for example, with
+    /// ephemeral VMs.
+    ///
+    /// Setting this to false adds these flags:
+    /// 1. -oStrictHostKeyChecking=no
+    /// 2. -oUserKnownHostsFile=/dev/null
+    #[serde(skip_serializing_if = "is_true")]
+    pub strict_host_key_checking: bool,
+
+    /// Host key algorithms to accept, in the raw ssh_config syntax
+    /// ("-oHostKeyAlgorithms" option). See
+    /// [`SshParams::prefer_host_key_type`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_key_algorithms: Option<String>,
+
+    /// Known-hosts files to use instead of the default
+    /// ("-oUserKnownHostsFile" option). All of the files are passed
+    /// in a single option and ssh checks each of them. Ignored if
+    /// `strict_host_key_checking` is false, since that uses
+    /// "/dev/null".
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub known_hosts_files: Vec<PathBuf>,
+
+    /// Host key checking policy. `KnownHostsPolicy::SessionTemp` adds
+    /// "-oStrictHostKeyChecking=accept-new" and points
+    /// "-oUserKnownHostsFile" at the session's file. Ignored if
+    /// `strict_host_key_checking` is false.
+    #[serde(skip_serializing_if = "is_default_policy")]
+    pub known_hosts_policy: KnownHostsPolicy,
+
+    /// If true (the default), add "-oBatchMode=yes" so that ssh never
+    /// prompts for a password or passphrase. Set this to false for
+    /// interactive sessions. BatchMode is also left out if `password`
+    /// or `askpass` is set.
+    #[serde(skip_serializing_if = "is_true")]
+    pub batch_mode: bool,
+
+    /// Password for password authentication, for use with a wrapper
+    /// such as `sshpass` that enters it at ssh's prompt. ssh cannot
+    /// take a password as an argument, so this is never emitted.
+    /// However, BatchMode disables password authentication, so when
+    /// this is set "-oBatchMode=yes" is not emitted regardless of
+    /// `batch_mode`.
+    ///
+    /// The password is never serialized, and is shown as "***" in
+    /// `Debug` output.
+    #[serde(skip)]
+    pub password: Option<String>,
+
+    /// Askpass helper program for prompting for passwords and
+    /// passphrases, for example in a GUI tool. When set,
+    /// "-oBatchMode=yes" is not emitted, and commands created by
+    /// [`SshParams::to_command`] set the `SSH_ASKPASS` environment
+    /// variable to this path and `SSH_ASKPASS_REQUIRE` to "force".
+    /// The environment cannot be expressed in the argument list, so
+    /// this has no effect on [`SshParams::command`] other than
+    /// leaving out BatchMode. Requires OpenSSH 8.4 or later.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub askpass: Option<PathBuf>,
+
+    /// Ciphers to allow, in the raw ssh_config syntax ("-oCiphers"
+    /// option). The value is passed through verbatim, including
+    /// "none" for ssh builds that support it, which can help find out
+    /// whether encryption limits throughput. "none" sends all data,
+    /// including passwords, in the clear, so it must never be used
+    /// outside of a trusted test network.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ciphers: Option<String>,
+
+    /// MAC algorithms to allow, in the raw ssh_config syntax
+    /// ("-oMACs" option). As with `ciphers`, "none" is passed through
+    /// verbatim; it disables integrity protection entirely.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub macs: Option<String>,
+
+    /// Key exchange algorithms to allow, in the raw ssh_config syntax
+    /// ("-oKexAlgorithms" option).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kex_algorithms: Option<String>,
+
+    /// Go to the background just before running the remote command
+    /// ("-f" option).
+    #[serde(skip_serializing_if = "is_false")]
+    pub go_background: bool,
+
+    /// Force pseudo-terminal allocation ("-t" option).
+    #[serde(skip_serializing_if = "is_false")]
+    pub force_tty: bool,
+
+    /// Do not run a remote command ("-N" option). This is useful when
+    /// only setting up port forwards. When set, any remote command
+    /// arguments are dropped.
+    #[serde(skip_serializing_if = "is_false")]
+    pub no_remote_command: bool,
+
+    /// Port forwards, emitted in order.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub forwards: Vec<Forward>,
+
+    /// If true, render `forwards` in option form (for example
+    /// "-oLocalForward=8080 localhost:80") instead of flag form (for
+    /// example "-L 8080:localhost:80").
+    #[serde(skip_serializing_if = "is_false")]
+    pub forwards_as_options: bool,
+
+    /// Whether remote forwards may bind to addresses other than
+    /// loopback on the remote host ("-oGatewayPorts" option). Note
+    /// that the server's sshd_config must also allow this.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gateway_ports: Option<GatewayPorts>,
+
+    /// Connection sharing mode ("-oControlMaster" option). Sharing
+    /// ("multiplexing") lets many commands run over a single
+    /// connection, which is much faster than connecting each time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control_master: Option<ControlMaster>,
+
+    /// Path of the control socket used for connection sharing
+    /// ("-oControlPath" option). The path may contain ssh's percent
+    /// tokens such as "%r@%h:%p" or "%C", which ssh expands. The
+    /// special value "none" disables connection sharing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control_path: Option<PathBuf>,
+
+    /// How long the master connection stays open in the background
+    /// after the initial connection closes, in the raw ssh_config
+    /// syntax such as "yes", "no", "10m", or "600"
+    /// ("-oControlPersist" option).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control_persist: Option<String>,
+
+    /// Log verbosity ("-oLogLevel" option).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<LogLevel>,
+
+    /// Interval after which ssh sends a keep-alive message through
+    /// the encrypted channel if no data has been received from the
+    /// server ("-oServerAliveInterval" option). Rounded up to whole
+    /// seconds, so that a sub-second interval does not become zero,
+    /// which disables keep-alives.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_alive_interval: Option<Duration>,
+
+    /// Number of unanswered keep-alive messages after which ssh
+    /// disconnects ("-oServerAliveCountMax" option).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_alive_count_max: Option<u32>,
+
+    /// Whether to send TCP keep-alive messages ("-oTCPKeepAlive"
+    /// option).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tcp_keep_alive: Option<bool>,
+
+    /// Number of times to try connecting before giving up, one
+    /// second apart ("-oConnectionAttempts" option). This is useful
+    /// for VMs whose sshd takes a few seconds to start. Zero is
+    /// rejected by [`SshParams::validate`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection_attempts: Option<u32>,
+
+    /// Timeout for establishing the connection ("-oConnectTimeout"
+    /// option). Rounded up to whole seconds, so that a sub-second
+    /// timeout does not become zero, which disables the timeout.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<Duration>,
+
+    /// Names of local environment variables to send to the server
+    /// ("-oSendEnv" option, one per name). The server must accept
+    /// them with `AcceptEnv`. Names may contain "*" and "?"
+    /// wildcards but not "=".
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub send_env: Vec<String>,
+
+    /// Environment variables to set on the server, as name/value
+    /// pairs ("-oSetEnv" option, one per pair). The server must
+    /// accept them with `AcceptEnv`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub set_env: Vec<(String, String)>,
+
+    /// If true, run the remote command through a login shell so that
+    /// it sees the user's full environment (for example `PATH` set in
+    /// ".bash_profile").
+    ///
+    /// The remote command arguments are joined with spaces, exactly
+    /// as ssh itself would join them, and the result is passed as a
+    /// single quoted argument to `sh -lc`. The login shell therefore
+    /// interprets the same command text that the user's shell would
+    /// without this option, so shell syntax in untrusted arguments is
+    /// just as dangerous either way. Has no effect if there is no
+    /// remote command.
+    #[serde(skip_serializing_if = "is_false")]
+    pub login_shell: bool,
+
+    /// Local address to connect from, on machines with multiple
+    /// addresses ("-b" option).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bind_address: Option<String>,
+
+    /// When these parameters are used as a jump host in
+    /// [`SshParams::multi_hop`], forward the connection by running
+    /// this netcat program (for example "nc" or "ncat") on the jump
+    /// host as "nc %h %p", instead of using "ssh -W". This is for old
+    /// clients that do not support "-W". Netcat must be installed on
+    /// the jump host.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jump_via_netcat: Option<String>,
+
+    /// Command used to connect to the server instead of a direct TCP
+    /// connection ("-oProxyCommand" option), for example
+    /// "cloudflared access ssh --hostname %h". The command is passed
+    /// to ssh as-is, so percent tokens such as "%h" and "%p" are
+    /// expanded by ssh. This cannot be combined with jump hosts:
+    /// [`SshParams::multi_hop`] returns an error if a host it reaches
+    /// through a hop has a `proxy_command`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_command: Option<String>,
+
+    /// Force IPv4 ("-4") or IPv6 ("-6"). This is useful for dual-stack
+    /// hosts where one family is broken, such as a host with a bad
+    /// AAAA record. Defaults to `AddressFamily::Any`, which emits
+    /// nothing.
+    #[serde(skip_serializing_if = "is_any_family")]
+    pub address_family: AddressFamily,
+}
+
+impl fmt::Debug for SshParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SshParams")
+            .field("address", &self.address)
+            .field("program", &self.program)
+            .field("config_file", &self.config_file)
+            .field("ignore_unknown", &self.ignore_unknown)
+            .field("tag", &self.tag)
+            .field("identity", &self.identity)
+            .field("extra_identities", &self.extra_identities)
+            .field("identities_only", &self.identities_only)
+            .field("forward_agent", &self.forward_agent)
+            .field("password_authentication", &self.password_authentication)
+            .field(
+                "kbd_interactive_authentication",
+                &self.kbd_interactive_authentication,
+            )
+            .field("preferred_authentications", &self.preferred_authentications)
+            .field(
+                "number_of_password_prompts",
+                &self.number_of_password_prompts,
+            )
+            .field("user", &self.user)
+            .field("strict_host_key_checking", &self.strict_host_key_checking)
+            .field("host_key_algorithms", &self.host_key_algorithms)
+            .field("known_hosts_files", &self.known_hosts_files)
+            .field("known_hosts_policy", &self.known_hosts_policy)
+            .field("batch_mode", &self.batch_mode)
+            // Never reveal the password in logs.
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .field("askpass", &self.askpass)
+            .field("ciphers", &self.ciphers)
+            .field("macs", &self.macs)
+            .field("kex_algorithms", &self.kex_algorithms)
+            .field("go_background", &self.go_background)
+            .field("force_tty", &self.force_tty)
+            .field("no_remote_command", &self.no_remote_command)
+            .field("forwards", &self.forwards)
+            .field("forwards_as_options", &self.forwards_as_options)
+            .field("gateway_ports", &self.gateway_ports)
+            .field("control_master", &self.control_master)
+            .field("control_path", &self.control_path)
+            .field("control_persist", &self.control_persist)
+            .field("log_level", &self.log_level)
+            .field("server_alive_interval", &self.server_alive_interval)
+            .field("server_alive_count_max", &self.server_alive_count_max)
+            .field("tcp_keep_alive", &self.tcp_keep_alive)
+            .field("connection_attempts", &self.connection_attempts)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("send_env", &self.send_env)
+            .field("set_env", &self.set_env)
+            .field("login_shell", &self.login_shell)
+            .field("bind_address", &self.bind_address)
+            .field("jump_via_netcat", &self.jump_via_netcat)
+            .field("proxy_command", &self.proxy_command)
+            .field("address_family", &self.address_family)
+            .finish()
+    }
+}
+
+/// Stable encoding of a value for [`SshParams::fingerprint`]. Unlike
+/// `Debug` output, which Rust does not guarantee to be stable, the
+/// format is defined here. Variable-length values are prefixed with
+/// their length so that adjacent values cannot run together.
+trait FingerprintInput {
+    fn write_to(&self, out: &mut Vec<u8>);
+}
+
+impl FingerprintInput for u64 {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl FingerprintInput for u32 {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        u64::from(*self).write_to(out);
+    }
+}
+
+impl FingerprintInput for u16 {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        u64::from(*self).write_to(out);
+    }
+}
+
+impl FingerprintInput for bool {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.push(u8::from(*self));
+    }
+}
+
+impl FingerprintInput for [u8] {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        (self.len() as u64).write_to(out);
+        out.extend_from_slice(self);
+    }
+}
+
+impl FingerprintInput for str {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        self.as_bytes().write_to(out);
+    }
+}
+
+impl FingerprintInput for String {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        self.as_str().write_to(out);
+    }
+}
+
+impl FingerprintInput for PathBuf {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        self.as_os_str().as_encoded_bytes().write_to(out);
+    }
+}
+
+impl FingerprintInput for Duration {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        self.as_secs().write_to(out);
+        self.subsec_nanos().write_to(out);
+    }
+}
+
+impl<T: FingerprintInput> FingerprintInput for Option<T> {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            None => false.write_to(out),
+            Some(value) => {
+                true.write_to(out);
+                value.write_to(out);
+            }
+        }
+    }
+}
+
+impl<T: FingerprintInput> FingerprintInput for Vec<T> {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        (self.len() as u64).write_to(out);
+        for value in self {
+            value.write_to(out);
+        }
+    }
+}
+
+impl<A: FingerprintInput, B: FingerprintInput> FingerprintInput for (A, B) {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        self.0.write_to(out);
+        self.1.write_to(out);
+    }
+}
+
+impl FingerprintInput for Address {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        self.host.write_to(out);
+        self.port.write_to(out);
+    }
+}
+
+impl FingerprintInput for Forward {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        let kind = match self.kind {
+            ForwardKind::Local => "local",
+            ForwardKind::Remote => "remote",
+            ForwardKind::Dynamic => "dynamic",
+        };
+        kind.write_to(out);
+        self.listen.write_to(out);
+        self.destination.write_to(out);
+    }
+}
+
+impl FingerprintInput for ForwardAgent {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            ForwardAgent::No => "no".write_to(out),
+            ForwardAgent::Yes => "yes".write_to(out),
+            ForwardAgent::Socket(path) => {
+                "socket".write_to(out);
+                path.write_to(out);
+            }
+        }
+    }
+}
+
+impl FingerprintInput for KnownHostsPolicy {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            KnownHostsPolicy::Default => "default".write_to(out),
+            KnownHostsPolicy::Ignore => "ignore".write_to(out),
+            KnownHostsPolicy::SessionTemp(path) => {
+                "sessiontemp".write_to(out);
+                path.write_to(out);
+            }
+        }
+    }
+}
+
+// These are encoded with their ssh_config value.
+impl FingerprintInput for GatewayPorts {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        self.to_string().write_to(out);
+    }
+}
+
+impl FingerprintInput for ControlMaster {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        self.to_string().write_to(out);
+    }
+}
+
+impl FingerprintInput for LogLevel {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        self.to_string().write_to(out);
+    }
+}
+
+impl FingerprintInput for AddressFamily {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        self.to_string().write_to(out);
+    }
+}
+
+/// Builder for remote command arguments of mixed types, for use with
+/// [`SshParams::command_args`].
+///
+/// ```
+/// use nbssh::SshArgs;
+/// use std::path::PathBuf;
+///
+/// let path = PathBuf::from("/var/log");
+/// let args = SshArgs::new().push_str("ls").push_path(&path);
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SshArgs {
+    args: Vec<OsString>,
+}
+
+impl SshArgs {
+    /// Create an empty argument list.
+    pub fn new() -> SshArgs {
+        SshArgs::default()
+    }
+
+    /// Add a string argument.
+    pub fn push_str(mut self, arg: &str) -> SshArgs {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Add a path argument.
+    pub fn push_path(mut self, arg: &Path) -> SshArgs {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Add an OS string argument.
+    pub fn push_os(mut self, arg: &OsStr) -> SshArgs {
+        self.args.push(arg.into());
+        self
+    }
+}
+
+/// Remote command rendered as a single shell command string.
+///
+/// ssh joins its remote command arguments with spaces and passes the
+/// result to the remote user's shell, so arguments containing spaces,
+/// quotes, or glob characters are not preserved. `RemoteCommand`
+/// quotes a program and its arguments so that the remote shell sees
+/// them unchanged. It implements `AsRef<OsStr>`, so it can be passed
+/// directly to [`SshParams::command`]:
+///
+/// ```
+/// use nbssh::{Address, RemoteCommand, SshParams};
+///
+/// let params = SshParams {
+///     address: Address::from_host("myHost"),
+///     ..Default::default()
+/// };
+/// let remote = RemoteCommand::new("touch", &["my file.txt"]);
+/// let args = params.command(&[remote]);
+/// assert_eq!(args.last().unwrap(), "touch 'my file.txt'");
+/// ```
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct RemoteCommand(String);
+
+impl RemoteCommand {
+    /// Create a command that runs `program` with `args`, quoting each
+    /// of them for the remote shell.
+    pub fn new<S: AsRef<OsStr>>(program: &str, args: &[S]) -> RemoteCommand {
+        let mut command = shell_quote(program.as_ref());
+        for arg in args {
+            command.push(' ');
+            command.push_str(&shell_quote(arg.as_ref()));
+        }
+        RemoteCommand(command)
+    }
+
+    /// Create a command from a string that is passed to the remote
+    /// shell as-is, without any quoting.
+    pub fn raw(command: &str) -> RemoteCommand {
+        RemoteCommand(command.to_string())
+    }
+
+    /// Get the command string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<OsStr> for RemoteCommand {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl Display for RemoteCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Meaning of an ssh exit code. See
+/// [`SshParams::interpret_exit_code`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ExitKind {
+    /// The remote command succeeded (exit code 0).
+    Success,
+    /// ssh itself failed, for example because it could not connect
+    /// or authenticate (exit code 255).
+    SshError,
+    /// The remote command failed with the given exit code.
+    RemoteFailure(i32),
+}
+
+/// How [`SshParams::command_with_policy`] passes the remote command
+/// arguments to ssh.
+///
+/// ssh joins its remote command arguments with spaces and the remote
+/// user's shell then parses the result, so arguments containing
+/// spaces or shell syntax are not preserved by default.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum QuotingPolicy {
+    /// Pass each argument unchanged, as [`SshParams::command`] does.
+    /// The remote shell sees the arguments joined with spaces, so
+    /// `["echo", "a b"]` runs `echo a b`.
+    Raw,
+    /// Shell-quote each argument so that the remote shell sees each
+    /// one as a single word. `["echo", "a b"]` runs `echo 'a b'`.
+    EscapeEach,
+    /// Join the arguments with spaces into a single pre-formed
+    /// command string, passed as one argument. The remote shell
+    /// parses it the same way as `Raw`, but the local argument list
+    /// has a single entry. See [`SshParams::command_str`].
+    SingleString,
+}
+
+/// Why an argument appears in the output of
+/// [`SshParams::command_annotated`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ArgSource {
+    /// The ssh program (`program`).
+    Program,
+    /// The config file (`config_file`).
+    ConfigFile,
+    /// `ignore_unknown`.
+    IgnoreUnknown,
+    /// `tag`.
+    Tag,
+    /// Host key checking (`strict_host_key_checking`,
+    /// `known_hosts_files`, or `host_key_algorithms`).
+    HostKey,
+    /// Cipher, MAC, or key exchange algorithms.
+    Algorithm,
+    /// "-oBatchMode=yes" (`batch_mode`).
+    BatchMode,
+    /// Identity files and IdentitiesOnly.
+    Identity,
+    /// Agent forwarding (`forward_agent`).
+    Agent,
+    /// Authentication methods (PasswordAuthentication,
+    /// KbdInteractiveAuthentication, PreferredAuthentications, and
+    /// NumberOfPasswordPrompts).
+    Authentication,
+    /// The port ("-p").
+    Port,
+    /// The local bind address ("-b").
+    BindAddress,
+    /// Session mode flags ("-f", "-t", or "-N").
+    Mode,
+    /// Port forwarding (`forwards`).
+    Forwarding,
+    /// Connection sharing (ControlMaster, ControlPath, and
+    /// ControlPersist).
+    Multiplexing,
+    /// `log_level`.
+    Logging,
+    /// Keep-alive options (ServerAliveInterval, ServerAliveCountMax,
+    /// and TCPKeepAlive).
+    KeepAlive,
+    /// Address family ("-4" or "-6"), ConnectionAttempts, and
+    /// ConnectTimeout.
+    Connection,
+    /// SendEnv and SetEnv.
+    Environment,
+    /// ProxyCommand (`proxy_command`).
+    Proxy,
+    /// The target ("[user@]host").
+    Target,
+    /// The remote command.
+    RemoteArg,
+}
+
+/// Destination for the arguments generated by `build_command`.
+trait ArgSink {
+    fn push_arg(&mut self, source: ArgSource, arg: OsString);
+}
+
+impl ArgSink for Vec<OsString> {
+    fn push_arg(&mut self, _source: ArgSource, arg: OsString) {
+        self.push(arg);
+    }
+}
+
+impl ArgSink for Vec<(OsString, ArgSource)> {
+    fn push_arg(&mut self, source: ArgSource, arg: OsString) {
+        self.push((arg, source));
+    }
+}
+
+/// SSH parameter validation errors.
+#[derive(Clone, Debug, Eq, PartialEq, thiserror::Error)]
+pub enum SshParamsError {
+    /// The address host is empty.
+    #[error("host is empty")]
+    EmptyHost,
+
+    /// The address host starts with "-" or contains whitespace or
+    /// control characters, so ssh could read it as an option.
+    #[error("invalid host: {0}")]
+    InvalidHost(String),
+
+    /// `no_remote_command` is set but a remote command was given; ssh
+    /// would not run it.
+    #[error("remote command given with no_remote_command")]
+    UnexpectedRemoteCommand,
+
+    /// `go_background` and `force_tty` are both set but there is no
+    /// remote command to run.
+    #[error("background with forced TTY requires a remote command")]
+    BackgroundTtyWithoutCommand,
+
+    /// `connection_attempts` is zero.
+    #[error("connection attempts must be at least one")]
+    ZeroConnectionAttempts,
+
+    /// A `send_env` name contains "=".
+    #[error("invalid SendEnv name: {0}")]
+    InvalidSendEnv(String),
+
+    /// A `set_env` name is not a valid environment variable name.
+    #[error("invalid SetEnv name: {0}")]
+    InvalidSetEnv(String),
+
+    /// `identities_only` is `Some(true)` but there is no identity, so
+    /// ssh has no key to offer.
+    #[error("IdentitiesOnly is set but no identity is provided")]
+    IdentitiesOnlyWithoutIdentity,
+
+    /// A [`ForwardAgent::Socket`] path does not exist or is not a
+    /// socket.
+    #[error("agent socket not found: {}", .0.display())]
+    InvalidAgentSocket(PathBuf),
+
+    /// A remote environment variable name is not a valid environment
+    /// variable name.
+    #[error("invalid remote environment variable name: {0}")]
+    InvalidRemoteEnv(String),
+
+    /// A remote command is required but none was given.
+    #[error("no remote command given")]
+    MissingRemoteCommand,
+
+    /// A host reached through jump hosts has its own `proxy_command`,
+    /// which conflicts with the jump chain.
+    #[error("ProxyCommand cannot be combined with jump hosts")]
+    ProxyCommandWithHops,
+}
+
+/// ssh_config parse and render errors.
+#[derive(Clone, Debug, Eq, PartialEq, thiserror::Error)]
+pub enum ConfigError {
+    /// The stanza contains more than one "Host" line.
+    #[error("config contains more than one Host stanza")]
+    MultipleHosts,
+
+    /// There is neither a "HostName" nor a "Host" to connect to.
+    #[error("config has no HostName")]
+    MissingHostName,
+
+    /// A keyword has no value.
+    #[error("missing value for {0}")]
+    MissingValue(String),
+
+    /// A keyword's value could not be parsed.
+    #[error("invalid value for {keyword}: {value}")]
+    InvalidValue {
+        /// Keyword as written in the config.
+        keyword: String,
+        /// Value that could not be parsed.
+        value: String,
+    },
+}
+
+/// Check whether `name` matches "[A-Za-z_][A-Za-z0-9_]*".
+fn is_valid_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+impl Default for SshParams {
+    fn default() -> SshParams {
+        SshParams {
+            address: Address::default(),
+            program: DEFAULT_PROGRAM.into(),
+            config_file: None,
+            ignore_unknown: Vec::new(),
+            tag: None,
+            identity: None,
+            extra_identities: Vec::new(),
+            identities_only: None,
+            forward_agent: None,
+            password_authentication: None,
+            kbd_interactive_authentication: None,
+            preferred_authentications: Vec::new(),
+            number_of_password_prompts: None,
+            user: None,
+            strict_host_key_checking: true,
+            host_key_algorithms: None,
+            known_hosts_files: Vec::new(),
+            known_hosts_policy: KnownHostsPolicy::default(),
+            batch_mode: true,
+            password: None,
+            askpass: None,
+            ciphers: None,
+            macs: None,
+            kex_algorithms: None,
+            go_background: false,
+            force_tty: false,
+            no_remote_command: false,
+            forwards: Vec::new(),
+            forwards_as_options: false,
+            gateway_ports: None,
+            control_master: None,
+            control_path: None,
+            control_persist: None,
+            log_level: None,
+            server_alive_interval: None,
+            server_alive_count_max: None,
+            tcp_keep_alive: None,
+            connection_attempts: None,
+            connect_timeout: None,
+            send_env: Vec::new(),
+            set_env: Vec::new(),
+            login_shell: false,
+            bind_address: None,
+            jump_via_netcat: None,
+            proxy_command: None,
+            address_family: AddressFamily::default(),
+        }
+    }
+}
+
+impl SshParams {
+    /// Create parameters with keep-alives suited to flaky networks
+    /// and NAT traversal: a server-alive message every 15 seconds,
+    /// disconnecting after 3 unanswered messages, plus TCP
+    /// keep-alives. Other fields are set to their defaults, and any
+    /// field can be overridden afterwards:
+    ///
+    /// ```
+    /// use nbssh::{Address, SshParams};
+    ///
+    /// let params = SshParams {
+    ///     address: Address::from_host("myHost"),
+    ///     server_alive_count_max: Some(5),
+    ///     ..SshParams::keep_alive()
+    /// };
+    /// ```
+    pub fn keep_alive() -> SshParams {
+        SshParams {
+            server_alive_interval: Some(Duration::from_secs(15)),
+            server_alive_count_max: Some(3),
+            tcp_keep_alive: Some(true),
+            ..Default::default()
+        }
+    }
+
+    /// Iterate over all explicit identities, starting with
+    /// `identity` followed by `extra_identities`.
+    pub fn identities(&self) -> impl Iterator<Item = &PathBuf> {
+        self.identity.iter().chain(self.extra_identities.iter())
+    }
+
+    /// Create parameters that bound how long connecting and running
+    /// can hang: each connection attempt times out after `secs`
+    /// seconds and is retried up to 3 times in total, and an
+    /// established connection is dropped after 3 server-alive
+    /// messages sent `secs` seconds apart go unanswered. Other fields
+    /// are set to their defaults and can be overridden as with
+    /// [`SshParams::keep_alive`].
+    pub fn robust_connect(secs: u32) -> SshParams {
+        let secs = Duration::from_secs(secs.into());
+        SshParams {
+            connect_timeout: Some(secs),
+            connection_attempts: Some(3),
+            server_alive_interval: Some(secs),
+            server_alive_count_max: Some(3),
+            ..Default::default()
+        }
+    }
+
+    /// Create parameters that only allow public key authentication,
+    /// so that ssh fails rather than falling back to a password or
+    /// keyboard-interactive prompt. This is useful in CI. Other
+    /// fields are set to their defaults and can be overridden as with
+    /// [`SshParams::keep_alive`].
+    pub fn key_only() -> SshParams {
+        SshParams {
+            password_authentication: Some(false),
+            kbd_interactive_authentication: Some(false),
+            preferred_authentications: vec!["publickey".to_string()],
+            ..Default::default()
+        }
+    }
+
+    /// Create parameters from a target in "[user@]host[:port]"
+    /// format. If a "user@" prefix is present it is used to set
+    /// `user` rather than being treated as part of the host. All
+    /// other fields are set to their defaults.
+    pub fn from_target(spec: &str) -> Result<SshParams, AddressError> {
+        let (address, user) = SshParams::parse_target(spec)?;
+        Ok(SshParams {
+            address,
+            user,
+            ..Default::default()
+        })
+    }
+
+    /// Parse a target in "[user@]host[:port]" format into an address
+    /// and an optional user name. IPv6 hosts must be enclosed in
+    /// brackets if a port is given, for example "me@[::1]:2222"; an
+    /// "@" inside the brackets is not treated as a user separator.
+    pub fn parse_target(
+        spec: &str,
+    ) -> Result<(Address, Option<String>), AddressError> {
+        let user_end = spec.find('[').unwrap_or(spec.len());
+        match spec[..user_end].rfind('@') {
+            Some(index) => {
+                let user = &spec[..index];
+                if user.is_empty() {
+                    return Err(AddressError::InvalidFormat);
+                }
+                let address = spec[index + 1..].parse()?;
+                Ok((address, Some(user.to_string())))
+            }
+            None => Ok((spec.parse()?, None)),
+        }
+    }
+
+    /// Get a copy of the parameters with all port forwarding removed,
+    /// for reusing a tunnel profile to just run a command. This also
+    /// clears `no_remote_command` and `gateway_ports`.
+    pub fn without_forwards(&self) -> SshParams {
+        SshParams {
+            forwards: Vec::new(),
+            gateway_ports: None,
+            no_remote_command: false,
+            ..self.clone()
+        }
+    }
+
+    /// Add an environment variable to `set_env`. Returns an error if
+    /// `name` is not a valid environment variable name (letters,
+    /// digits, and underscores, not starting with a digit), since ssh
+    /// silently ignores such variables.
+    pub fn try_set_env(
+        &mut self,
+        name: &str,
+        value: &str,
+    ) -> Result<(), SshParamsError> {
+        if !is_valid_env_name(name) {
+            return Err(SshParamsError::InvalidSetEnv(name.to_string()));
+        }
+        self.set_env.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    /// Prefer host keys of type `key_type` (for example "ed25519")
+    /// by moving its algorithms to the front of the default list
+    /// using the "^" modifier. This avoids extra round trips when the
+    /// host's key type is already known.
+    ///
+    /// The short names "ed25519", "ecdsa", and "rsa" are expanded to
+    /// their full algorithm names; anything else is used as-is.
+    pub fn prefer_host_key_type(&mut self, key_type: &str) {
+        let algorithms = match key_type {
+            "ed25519" => "ssh-ed25519",
+            "ecdsa" => {
+                "ecdsa-sha2-nistp256,ecdsa-sha2-nistp384,ecdsa-sha2-nistp521"
+            }
+            "rsa" => "rsa-sha2-512,rsa-sha2-256,ssh-rsa",
+            other => other,
+        };
+        self.host_key_algorithms = Some(format!("^{}", algorithms));
+    }
+
+    /// Check that the parameters make sense together when running
+    /// the remote command `args`.
+    pub fn validate<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+    ) -> Result<(), SshParamsError> {
+        if self.address.host.is_empty() {
+            return Err(SshParamsError::EmptyHost);
+        }
+        if check_host(&self.address.host).is_err() {
+            return Err(SshParamsError::InvalidHost(self.address.host.clone()));
+        }
+        if self.no_remote_command && !args.is_empty() {
+            return Err(SshParamsError::UnexpectedRemoteCommand);
+        }
+        if self.go_background && self.force_tty && args.is_empty() {
+            return Err(SshParamsError::BackgroundTtyWithoutCommand);
+        }
+        if self.connection_attempts == Some(0) {
+            return Err(SshParamsError::ZeroConnectionAttempts);
+        }
+        if let Some(name) = self.send_env.iter().find(|n| n.contains('=')) {
+            return Err(SshParamsError::InvalidSendEnv(name.clone()));
+        }
+        if let Some((name, _)) =
+            self.set_env.iter().find(|(n, _)| !is_valid_env_name(n))
+        {
+            return Err(SshParamsError::InvalidSetEnv(name.clone()));
+        }
+        if self.identities_only == Some(true) && self.identities().count() == 0
+        {
+            return Err(SshParamsError::IdentitiesOnlyWithoutIdentity);
+        }
+        #[cfg(unix)]
+        if let Some(ForwardAgent::Socket(path)) = &self.forward_agent {
+            use std::os::unix::fs::FileTypeExt;
+
+            // Environment variable names are resolved by ssh.
+            let is_env_var = path.to_string_lossy().starts_with('$');
+            let is_socket = std::fs::metadata(path)
+                .map(|metadata| metadata.file_type().is_socket())
+                .unwrap_or(false);
+            if !is_env_var && !is_socket {
+                return Err(SshParamsError::InvalidAgentSocket(path.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the remote user name that ssh will use. If `user` is not
+    /// set this is the local user name.
+    fn remote_user(&self) -> String {
+        self.user.clone().unwrap_or_else(local_user)
+    }
+
+    /// Get the hash that ssh substitutes for the "%C" token: the
+    /// SHA-1 of the local host name, remote host name, port, and
+    /// remote user name, as a hex string.
+    ///
+    /// The local host name is read with `gethostname` on Unix, as ssh
+    /// does. On other platforms it is taken from the environment, so
+    /// the hash may not match ssh's.
+    pub fn control_path_hash(&self) -> String {
+        let input = format!(
+            "{}{}{}{}",
+            local_hostname(),
+            self.address.host,
+            self.address.effective_port(),
+            self.remote_user()
+        );
+        sha1::sha1_hex(input.as_bytes())
+    }
+
+    /// Get a short hash of all fields except `password`, as 16 hex
+    /// digits. This is deterministic, so it can be used as a cache key
+    /// for generated artifacts such as wrapper scripts, and it changes
+    /// when any field other than `password` changes. The password is
+    /// left out so that it cannot be recovered from the hash. The
+    /// value may differ between versions of this crate.
+    pub fn fingerprint(&self) -> String {
+        // Destructure so that a new field cannot be forgotten here.
+        let SshParams {
+            address,
+            program,
+            config_file,
+            ignore_unknown,
+            tag,
+            identity,
+            extra_identities,
+            identities_only,
+            forward_agent,
+            password_authentication,
+            kbd_interactive_authentication,
+            preferred_authentications,
+            number_of_password_prompts,
+            user,
+            strict_host_key_checking,
+            host_key_algorithms,
+            known_hosts_files,
+            known_hosts_policy,
+            batch_mode,
+            password: _,
+            askpass,
+            ciphers,
+            macs,
+            kex_algorithms,
+            go_background,
+            force_tty,
+            no_remote_command,
+            forwards,
+            forwards_as_options,
+            gateway_ports,
+            control_master,
+            control_path,
+            control_persist,
+            log_level,
+            server_alive_interval,
+            server_alive_count_max,
+            tcp_keep_alive,
+            connection_attempts,
+            connect_timeout,
+            send_env,
+            set_env,
+            login_shell,
+            bind_address,
+            jump_via_netcat,
+            proxy_command,
+            address_family,
+        } = self;
+        let fields: &[&dyn FingerprintInput] = &[
+            address,
+            program,
+            config_file,
+            ignore_unknown,
+            tag,
+            identity,
+            extra_identities,
+            identities_only,
+            forward_agent,
+            password_authentication,
+            kbd_interactive_authentication,
+            preferred_authentications,
+            number_of_password_prompts,
+            user,
+            strict_host_key_checking,
+            host_key_algorithms,
+            known_hosts_files,
+            known_hosts_policy,
+            batch_mode,
+            askpass,
+            ciphers,
+            macs,
+            kex_algorithms,
+            go_background,
+            force_tty,
+            no_remote_command,
+            forwards,
+            forwards_as_options,
+            gateway_ports,
+            control_master,
+            control_path,
+            control_persist,
+            log_level,
+            server_alive_interval,
+            server_alive_count_max,
+            tcp_keep_alive,
+            connection_attempts,
+            connect_timeout,
+            send_env,
+            set_env,
+            login_shell,
+            bind_address,
+            jump_via_netcat,
+            proxy_command,
+            address_family,
+        ];
+        let mut input = Vec::new();
+        for field in fields {
+            field.write_to(&mut input);
+        }
+        let mut hash = sha1::sha1_hex(&input);
+        hash.truncate(16);
+        hash
+    }
+
+    /// Expand ssh's percent tokens in `path`. The supported tokens
+    /// are "%%", "%h" (remote host), "%p" (port), "%r" (remote
+    /// user), "%u" (local user), "%l" (local host name), and "%C"
+    /// (see [`SshParams::control_path_hash`]). Other tokens are left
+    /// as-is.
+    fn expand_path_tokens(&self, path: &Path) -> PathBuf {
+        let path = path.to_string_lossy();
+        let mut output = String::new();
+        let mut chars = path.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                output.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => output.push('%'),
+                Some('h') => output.push_str(&self.address.host),
+                Some('p') => {
+                    output.push_str(&self.address.effective_port().to_string())
+                }
+                Some('r') => output.push_str(&self.remote_user()),
+                Some('u') => output.push_str(&local_user()),
+                Some('l') => output.push_str(&local_hostname()),
+                Some('C') => output.push_str(&self.control_path_hash()),
+                Some(other) => {
+                    output.push('%');
+                    output.push(other);
+                }
+                None => output.push('%'),
+            }
+        }
+        output.into()
+    }
+
+    /// Get the control socket path with percent tokens and then a
+    /// leading "~" expanded, as ssh does. If `control_path` is unset
+    /// or "none", returns `None`.
+    pub fn expanded_control_path(&self) -> Option<PathBuf> {
+        let path = self.control_path.as_ref()?;
+        if path == Path::new("none") {
+            None
+        } else {
+            Some(expand_home_path(&self.expand_path_tokens(path)))
+        }
+    }
+
+    /// Check whether a ControlMaster socket exists at the expanded
+    /// control path (see [`SshParams::expanded_control_path`]). On Unix
+    /// the path must be a socket, not just any file. This does not
+    /// check that a master process is still listening on it; use
+    /// "ssh -O check" for that.
+    pub fn master_socket_exists(&self) -> bool {
+        let path = match self.expanded_control_path() {
+            Some(path) => path,
+            None => return false,
+        };
+        match std::fs::metadata(path) {
+            #[cfg(unix)]
+            Ok(metadata) => {
+                use std::os::unix::fs::FileTypeExt;
+                metadata.file_type().is_socket()
+            }
+            #[cfg(not(unix))]
+            Ok(_) => true,
+            Err(_) => false,
+        }
+    }
+
+    /// Get a copy of the parameters with ssh's percent tokens expanded
+    /// in the identity paths and control path, so that the concrete
+    /// paths can be inspected. The supported tokens are "%%", "%h"
+    /// (remote host), "%p" (port), "%r" (remote user), "%u" (local
+    /// user), "%l" (local host name), and "%C" (see
+    /// [`SshParams::control_path_hash`]). Other tokens are left as-is.
+    pub fn expand_tokens(&self) -> SshParams {
+        SshParams {
+            identity: self
+                .identity
+                .as_ref()
+                .map(|path| self.expand_path_tokens(path)),
+            extra_identities: self
+                .extra_identities
+                .iter()
+                .map(|path| self.expand_path_tokens(path))
+                .collect(),
+            control_path: self
+                .control_path
+                .as_ref()
+                .map(|path| self.expand_path_tokens(path)),
+            ..self.clone()
+        }
+    }
+
+    /// Get a copy of the parameters with a leading "~" or "~user"
+    /// expanded to the home directory in the identity paths,
+    /// `known_hosts_files`, and `config_file`. ssh expands these
+    /// itself, so this is only needed to inspect the files, for
+    /// example to check that they exist. The current user's home
+    /// directory is read from the `HOME` environment variable, and
+    /// other users' from "/etc/passwd"; paths whose home directory
+    /// cannot be found are left unchanged.
+    pub fn expand_home(&self) -> SshParams {
+        let expand_all = |paths: &[PathBuf]| -> Vec<PathBuf> {
+            paths.iter().map(|path| expand_home_path(path)).collect()
+        };
+        SshParams {
+            identity: self.identity.as_deref().map(expand_home_path),
+            extra_identities: expand_all(&self.extra_identities),
+            known_hosts_files: expand_all(&self.known_hosts_files),
+            config_file: self.config_file.as_deref().map(expand_home_path),
+            ..self.clone()
+        }
+    }
+
+    /// Check whether `self` and `other` would use the same ControlMaster
+    /// socket, meaning that commands from both can share a single
+    /// connection. This compares the expanded control paths. A
+    /// control path of "none" (or no control path) never shares.
+    pub fn shares_control_master(&self, other: &SshParams) -> bool {
+        match (self.expanded_control_path(), other.expanded_control_path()) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// Get the IdentitiesOnly setting to emit, if any. When
+    /// `identities_only` is unset this is enabled automatically for
+    /// more than one explicit identity.
+    fn effective_identities_only(&self) -> Option<bool> {
+        self.identities_only.or_else(|| {
+            if self.identities().count() > 1 {
+                Some(true)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Get the host key checking options as ssh_config name and value
+    /// pairs.
+    fn host_key_options(&self) -> Vec<(&'static str, String)> {
+        if !self.strict_host_key_checking
+            || self.known_hosts_policy == KnownHostsPolicy::Ignore
+        {
+            return vec![
+                ("StrictHostKeyChecking", "no".into()),
+                ("UserKnownHostsFile", "/dev/null".into()),
+            ];
+        }
+        match &self.known_hosts_policy {
+            KnownHostsPolicy::SessionTemp(path) => vec![
+                ("StrictHostKeyChecking", "accept-new".into()),
+                ("UserKnownHostsFile", config_quote(&path.to_string_lossy())),
+            ],
+            _ if !self.known_hosts_files.is_empty() => {
+                vec![("UserKnownHostsFile", self.known_hosts_value())]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Get the authentication method options as ssh_config name and
+    /// value pairs.
+    fn authentication_options(&self) -> Vec<(&'static str, String)> {
+        let mut options = Vec::new();
+        if let Some(enabled) = self.password_authentication {
+            options.push(("PasswordAuthentication", yes_no(enabled).into()));
+        }
+        if let Some(enabled) = self.kbd_interactive_authentication {
+            options
+                .push(("KbdInteractiveAuthentication", yes_no(enabled).into()));
+        }
+        if !self.preferred_authentications.is_empty() {
+            options.push((
+                "PreferredAuthentications",
+                self.preferred_authentications.join(","),
+            ));
+        }
+        if let Some(prompts) = self.number_of_password_prompts {
+            options.push(("NumberOfPasswordPrompts", prompts.to_string()));
+        }
+        options
+    }
+
+    /// Get the `known_hosts_files` as a single UserKnownHostsFile
+    /// value.
+    fn known_hosts_value(&self) -> String {
+        self.known_hosts_files
+            .iter()
+            .map(|path| config_quote(&path.to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Get the ssh target in "[user@]host" format. IPv6 hosts are not
+    /// bracketed.
+    fn target(&self) -> String {
+        if let Some(user) = &self.user {
+            format!("{}@{}", user, self.address.host)
+        } else {
+            self.address.host.clone()
+        }
+    }
+
+    /// Get the options in use that require a minimum ssh version,
+    /// along with that version.
+    fn version_requirements(&self) -> Vec<(&'static str, SshVersion)> {
+        let mut requirements = Vec::new();
+        if !self.set_env.is_empty() {
+            requirements.push(("SetEnv", SshVersion::new(7, 8)));
+        }
+        if self.tag.is_some() {
+            requirements.push(("Tag", SshVersion::new(9, 4)));
+        }
+        if self.askpass.is_some() {
+            requirements.push(("SSH_ASKPASS_REQUIRE", SshVersion::new(8, 4)));
+        }
+        if self
+            .host_key_options()
+            .contains(&("StrictHostKeyChecking", "accept-new".into()))
+        {
+            requirements.push((
+                "StrictHostKeyChecking=accept-new",
+                SshVersion::new(7, 6),
+            ));
+        }
+        requirements
+    }
+
+    /// Check for options that the ssh version `version` does not
+    /// support. Returns a warning for each such option; the list is
+    /// empty if everything is supported. See also
+    /// [`detect_ssh_version`].
+    pub fn validate_for_version(
+        &self,
+        version: &SshVersion,
+    ) -> Vec<VersionWarning> {
+        self.version_requirements()
+            .into_iter()
+            .filter(|(_, required)| version < required)
+            .map(|(option, required)| VersionWarning { option, required })
+            .collect()
+    }
+
+    /// Create a full SSH command.
+    pub fn command<S: AsRef<OsStr>>(&self, args: &[S]) -> Vec<OsString> {
+        let mut output = Vec::new();
+        self.command_into(&mut output, args);
+        output
+    }
+
+    /// Create a full SSH command that runs the pre-quoted shell command
+    /// `remote`, which is passed as a single trailing argument and
+    /// interpreted by the remote user's shell.
+    ///
+    /// Note that ssh joins multiple remote arguments with spaces
+    /// before passing them to the remote shell, so
+    /// `command(&["a", "b", "c"])` runs the same remote command as
+    /// `command_str("a b c")`. The difference is in the local argv:
+    /// `command` emits three arguments and `command_str` emits one,
+    /// which matters for wrappers such as
+    /// [`SshParams::command_string_redacted`] that quote each
+    /// argument.
+    pub fn command_str(&self, remote: &str) -> Vec<OsString> {
+        self.command(&[remote])
+    }
+
+    /// Check the parameters with [`SshParams::validate`] and then
+    /// create a full SSH command.
+    pub fn try_command<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+    ) -> Result<Vec<OsString>, SshParamsError> {
+        self.validate(args)?;
+        Ok(self.command(args))
+    }
+
+    /// Create a full SSH command and resolve the host with
+    /// [`Address::resolve`], returning the first resolved address.
+    /// This is useful for logging the IP address being connected to;
+    /// note that ssh does its own lookup and could in principle pick
+    /// a different address. Returns an error if the host does not
+    /// resolve to any address.
+    ///
+    /// This performs a blocking DNS lookup.
+    pub fn command_and_resolved<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+    ) -> io::Result<(Vec<OsString>, SocketAddr)> {
+        let resolved =
+            self.address.resolve()?.into_iter().next().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} did not resolve", self.address.host),
+                )
+            })?;
+        Ok((self.command(args), resolved))
+    }
+
+    /// Create a full SSH command, passing `args` to ssh according to
+    /// `policy`.
+    pub fn command_with_policy<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+        policy: QuotingPolicy,
+    ) -> Vec<OsString> {
+        match policy {
+            QuotingPolicy::Raw => self.command(args),
+            QuotingPolicy::EscapeEach => {
+                let quoted: Vec<String> =
+                    args.iter().map(|arg| shell_quote(arg.as_ref())).collect();
+                self.command(&quoted)
+            }
+            QuotingPolicy::SingleString => {
+                let joined = args
+                    .iter()
+                    .map(|arg| arg.as_ref().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                self.command_str(&joined)
+            }
+        }
+    }
+
+    /// Create a full SSH command with the remote command `args`.
+    pub fn command_args(&self, args: SshArgs) -> Vec<OsString> {
+        self.command(&args.args)
+    }
+
+    /// Create a full SSH command in `buf`. The buffer is cleared
+    /// first, but its allocation is reused, so calling this
+    /// repeatedly with the same buffer avoids allocating a new vector
+    /// for each command.
+    pub fn command_into<S: AsRef<OsStr>>(
+        &self,
+        buf: &mut Vec<OsString>,
+        args: &[S],
+    ) {
+        buf.clear();
+        self.build_command(buf, args, false);
+    }
+
+    /// Create an SSH command that only contains options that were
+    /// explicitly set, leaving out "-oBatchMode=yes" which
+    /// [`SshParams::command`] adds by default. For a host alias defined
+    /// in "~/.ssh/config" with no other fields set, this is just the
+    /// program, the alias, and `args`, so that the config fully
+    /// controls the connection.
+    ///
+    /// Options that are set to ssh's built-in default are also left
+    /// out to produce the shortest equivalent command:
+    /// `DEFAULT_SSH_PORT`,
+    /// `LogLevel::Info`, TCPKeepAlive enabled, and a single connection
+    /// attempt. Note that if "~/.ssh/config" overrides one of these
+    /// for the host, the config's value is used instead.
+    pub fn command_minimal<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+    ) -> Vec<OsString> {
+        let mut output = Vec::new();
+        self.build_command(&mut output, args, true);
+        output
+    }
+
+    /// Get just the ssh options (such as "-i", "-p", and "-o...") that
+    /// [`SshParams::command`] would emit, without the program name,
+    /// target, or remote command. Session mode flags ("-f", "-t", and
+    /// "-N") and port forwarding are also left out, since the other
+    /// tool decides what the session does. This is useful for passing
+    /// the same connection settings to another tool that runs ssh.
+    pub fn connection_flags(&self) -> Vec<OsString> {
+        self.command_annotated::<&str>(&[])
+            .into_iter()
+            .filter(|(_, source)| {
+                !matches!(
+                    source,
+                    ArgSource::Program
+                        | ArgSource::Mode
+                        | ArgSource::Forwarding
+                        | ArgSource::Target
+                )
+            })
+            .map(|(arg, _)| arg)
+            .collect()
+    }
+
+    /// Get a value for git's `GIT_SSH_COMMAND` environment variable:
+    /// the program and [`SshParams::connection_flags`], shell-quoted
+    /// as a single string. git appends the target and remote command
+    /// itself, so they are not included.
+    pub fn git_ssh_command(&self) -> OsString {
+        let mut command = vec![OsString::from(&self.program)];
+        command.extend(self.connection_flags());
+        shell_join(&command).into()
+    }
+
+    /// Create a full SSH command for each of `hosts`, running the same
+    /// `args` on each. All options other than the address, such as the
+    /// user and identity, are shared.
+    pub fn fan_out<S: AsRef<OsStr>>(
+        &self,
+        hosts: &[Address],
+        args: &[S],
+    ) -> Vec<Vec<OsString>> {
+        let mut params = self.clone();
+        hosts
+            .iter()
+            .map(|address| {
+                params.address = address.clone();
+                params.command(args)
+            })
+            .collect()
+    }
+
+    /// Read a host list file and create a full SSH command for each
+    /// host, running the same `args` on each. The file has one
+    /// "[user@]host[:port]" target per line; blank lines and lines
+    /// starting with "#" are ignored. A user or port given on a line
+    /// overrides the one in `self`. See also [`SshParams::fan_out`].
+    ///
+    /// An invalid target is reported as an
+    /// [`io::ErrorKind::InvalidData`] error.
+    pub fn commands_from_host_file<S: AsRef<OsStr>>(
+        &self,
+        path: &Path,
+        args: &[S],
+    ) -> io::Result<Vec<(Address, Vec<OsString>)>> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut output = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (mut address, user) =
+                SshParams::parse_target(line).map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}:{}: {}", path.display(), index + 1, err),
+                    )
+                })?;
+            address.port = address.port.or(self.address.port);
+            let params = SshParams {
+                address: address.clone(),
+                user: user.or_else(|| self.user.clone()),
+                ..self.clone()
+            };
+            output.push((address, params.command(args)));
+        }
+        Ok(output)
+    }
+
+    /// Append the SSH command to `output`.
+    fn build_command<O: ArgSink, S: AsRef<OsStr>>(
+        &self,
+        output: &mut O,
+        args: &[S],
+        minimal: bool,
+    ) {
+        use ArgSource::*;
+
+        output.push_arg(Program, self.program.clone().into());
+
+        if let Some(config_file) = &self.config_file {
+            output.push_arg(ConfigFile, "-F".into());
+            output.push_arg(ConfigFile, config_file.into());
+        }
+        if !self.ignore_unknown.is_empty() {
+            output.push_arg(
+                IgnoreUnknown,
+                format!("-oIgnoreUnknown={}", self.ignore_unknown.join(","))
+                    .into(),
+            );
+        }
+        if let Some(tag) = &self.tag {
+            output.push_arg(Tag, format!("-oTag={}", tag).into());
+        }
+
+        for (name, value) in self.host_key_options() {
+            output.push_arg(HostKey, format!("-o{}={}", name, value).into());
+        }
+        if let Some(algorithms) = &self.host_key_algorithms {
+            output.push_arg(
+                HostKey,
+                format!("-oHostKeyAlgorithms={}", algorithms).into(),
+            );
+        }
+        if let Some(ciphers) = &self.ciphers {
+            output.push_arg(Algorithm, format!("-oCiphers={}", ciphers).into());
+        }
+        if let Some(macs) = &self.macs {
+            output.push_arg(Algorithm, format!("-oMACs={}", macs).into());
+        }
+        if let Some(algorithms) = &self.kex_algorithms {
+            output.push_arg(
+                Algorithm,
+                format!("-oKexAlgorithms={}", algorithms).into(),
+            );
+        }
+        if self.batch_mode
+            && self.password.is_none()
+            && self.askpass.is_none()
+            && !minimal
+        {
+            output.push_arg(BatchMode, "-oBatchMode=yes".into());
+        }
+
+        for identity in self.identities() {
+            output.push_arg(Identity, "-i".into());
+            output.push_arg(Identity, identity.into());
+        }
+        if let Some(identities_only) = self.effective_identities_only() {
+            output.push_arg(
+                Identity,
+                format!("-oIdentitiesOnly={}", yes_no(identities_only)).into(),
+            );
+        }
+        match &self.forward_agent {
+            None => {}
+            Some(ForwardAgent::No) => output.push_arg(Agent, "-a".into()),
+            Some(ForwardAgent::Yes) => output.push_arg(Agent, "-A".into()),
+            Some(ForwardAgent::Socket(path)) => {
+                let mut arg = OsString::from("-oForwardAgent=");
+                arg.push(path);
+                output.push_arg(Agent, arg);
+            }
+        }
+        for (name, value) in self.authentication_options() {
+            output.push_arg(
+                Authentication,
+                format!("-o{}={}", name, value).into(),
+            );
+        }
+
+        if self.address.port.is_some()
+            && !(minimal && self.address.is_default_port())
+        {
+            let (flag, port) = self.address.port_flag(Transport::Ssh);
+            output.push_arg(Port, flag.into());
+            output.push_arg(Port, port.into());
+        }
+
+        match self.address_family {
+            AddressFamily::Any => {}
+            AddressFamily::Inet => output.push_arg(Connection, "-4".into()),
+            AddressFamily::Inet6 => output.push_arg(Connection, "-6".into()),
+        }
+        if let Some(bind_address) = &self.bind_address {
+            output.push_arg(BindAddress, "-b".into());
+            output.push_arg(BindAddress, bind_address.into());
+        }
+
+        if self.go_background {
+            output.push_arg(Mode, "-f".into());
+        }
+        if self.force_tty {
+            output.push_arg(Mode, "-t".into());
+        }
+        if self.no_remote_command {
+            output.push_arg(Mode, "-N".into());
+        }
+
+        for forward in &self.forwards {
+            if self.forwards_as_options {
+                output.push_arg(Forwarding, forward.to_option_arg());
+            } else {
+                for arg in forward.to_flag_args() {
+                    output.push_arg(Forwarding, arg);
+                }
+            }
+        }
+        if let Some(gateway_ports) = self.gateway_ports {
+            output.push_arg(
+                Forwarding,
+                format!("-oGatewayPorts={}", gateway_ports).into(),
+            );
+        }
+
+        if let Some(master) = self.control_master {
+            output.push_arg(
+                Multiplexing,
+                format!("-oControlMaster={}", master).into(),
+            );
+        }
+        if let Some(path) = &self.control_path {
+            let mut arg = OsString::from("-oControlPath=");
+            arg.push(path);
+            output.push_arg(Multiplexing, arg);
+        }
+        if let Some(persist) = &self.control_persist {
+            output.push_arg(
+                Multiplexing,
+                format!("-oControlPersist={}", persist).into(),
+            );
+        }
+
+        if let Some(level) = self
+            .log_level
+            .filter(|level| !(minimal && *level == LogLevel::Info))
+        {
+            output.push_arg(Logging, format!("-oLogLevel={}", level).into());
+        }
+
+        if let Some(interval) = self.server_alive_interval {
+            output.push_arg(
+                KeepAlive,
+                format!("-oServerAliveInterval={}", ceil_secs(interval)).into(),
+            );
+        }
+        if let Some(count) = self.server_alive_count_max {
+            output.push_arg(
+                KeepAlive,
+                format!("-oServerAliveCountMax={}", count).into(),
+            );
+        }
+        if let Some(keep_alive) = self
+            .tcp_keep_alive
+            .filter(|keep_alive| !(minimal && *keep_alive))
+        {
+            output.push_arg(
+                KeepAlive,
+                format!("-oTCPKeepAlive={}", yes_no(keep_alive)).into(),
+            );
+        }
+
+        if let Some(attempts) =
+            self.connection_attempts.filter(|n| !(minimal && *n == 1))
+        {
+            output.push_arg(
+                Connection,
+                format!("-oConnectionAttempts={}", attempts).into(),
+            );
+        }
+        if let Some(timeout) = self.connect_timeout {
+            output.push_arg(
+                Connection,
+                format!("-oConnectTimeout={}", ceil_secs(timeout)).into(),
+            );
+        }
+
+        for name in &self.send_env {
+            output.push_arg(Environment, format!("-oSendEnv={}", name).into());
+        }
+        for (name, value) in &self.set_env {
+            output.push_arg(
+                Environment,
+                format!("-oSetEnv={}={}", name, config_quote(value)).into(),
+            );
+        }
+
+        if let Some(proxy_command) = &self.proxy_command {
+            output.push_arg(
+                Proxy,
+                format!("-oProxyCommand={}", proxy_command).into(),
+            );
+        }
+
+        output.push_arg(Target, self.target().into());
+        if self.no_remote_command {
+            // No remote command is run, so drop the arguments.
+        } else if self.login_shell && !args.is_empty() {
+            let joined = args
+                .iter()
+                .map(|arg| arg.as_ref().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" ");
+            output.push_arg(
+                RemoteArg,
+                shell_join(&["sh", "-lc", &joined]).into(),
+            );
+        } else {
+            for arg in args {
+                output.push_arg(RemoteArg, arg.into());
+            }
+        }
+    }
+
+    /// Create the full SSH command with each argument tagged with the
+    /// reason it was emitted. This is intended for debugging and for
+    /// explaining a command to users; the arguments are the same as
+    /// those returned by [`SshParams::command`].
+    pub fn command_annotated<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+    ) -> Vec<(OsString, ArgSource)> {
+        let mut output = Vec::new();
+        self.build_command(&mut output, args, false);
+        output
+    }
+
+    /// Create a [`Command`] that runs the full SSH command. If
+    /// `askpass` is set, the askpass environment variables are also
+    /// set on the command.
+    pub fn to_command<S: AsRef<OsStr>>(&self, args: &[S]) -> Command {
+        self.command_from_args(&self.command(args))
+    }
+
+    /// Create a [`Command`] from a full argument list, including the
+    /// program name, with the askpass environment applied.
+    fn command_from_args(&self, args: &[OsString]) -> Command {
+        let mut cmd = Command::new(&args[0]);
+        cmd.args(&args[1..]);
+        self.apply_askpass_env(&mut cmd);
+        cmd
+    }
+
+    /// Set the askpass environment variables on `cmd` if `askpass` is
+    /// set. The variables are inherited by ssh if `cmd` runs it
+    /// indirectly.
+    fn apply_askpass_env(&self, cmd: &mut Command) {
+        if let Some(askpass) = &self.askpass {
+            cmd.env("SSH_ASKPASS", askpass);
+            cmd.env("SSH_ASKPASS_REQUIRE", "force");
+        }
+    }
+
+    /// Append the full SSH command, including the program name, to the
+    /// arguments of `cmd`. This is useful for running ssh under
+    /// another program such as `timeout`. If `askpass` is set, the
+    /// askpass environment variables are also set on `cmd`, so that
+    /// ssh inherits them.
+    pub fn extend_command<S: AsRef<OsStr>>(
+        &self,
+        cmd: &mut Command,
+        args: &[S],
+    ) {
+        cmd.args(self.command(args));
+        self.apply_askpass_env(cmd);
+    }
+
+    /// Create a [`Command`] that runs the full SSH command, with
+    /// `arg0` as the first argument seen by the program instead of
+    /// the program path. This is useful for invoking a symlinked ssh
+    /// while presenting a specific name.
+    #[cfg(unix)]
+    pub fn to_command_with_arg0<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+        arg0: &OsStr,
+    ) -> Command {
+        use std::os::unix::process::CommandExt;
+
+        let mut cmd = self.to_command(args);
+        cmd.arg0(arg0);
+        cmd
+    }
+
+    /// Spawn the full SSH command as a [`tokio::process::Child`]. The
+    /// command is built with [`SshParams::to_command`], so stdio is
+    /// inherited. To capture output, use
+    /// [`SshParams::run_capture_tokio`].
+    ///
+    /// Requires the `tokio` feature.
+    #[cfg(feature = "tokio")]
+    pub fn spawn_tokio<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+    ) -> io::Result<tokio::process::Child> {
+        tokio::process::Command::from(self.to_command(args)).spawn()
+    }
+
+    /// Run the full SSH command asynchronously and capture its output,
+    /// like [`SshParams::run_capture`]. stdout and stderr are captured
+    /// rather than inherited, and stdin is null.
+    ///
+    /// Requires the `tokio` feature.
+    #[cfg(feature = "tokio")]
+    pub async fn run_capture_tokio<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+    ) -> io::Result<std::process::Output> {
+        let mut cmd = tokio::process::Command::from(self.to_command(args));
+        cmd.stdin(Stdio::null()).output().await
+    }
+
+    /// Interpret the exit code of an ssh process. By convention ssh
+    /// exits with 255 if an error occurred in ssh itself (such as a
+    /// connection or authentication failure); otherwise it exits with
+    /// the exit code of the remote command. Note that a remote
+    /// command that itself exits with 255 cannot be distinguished
+    /// from an ssh error.
+    pub fn interpret_exit_code(code: i32) -> ExitKind {
+        match code {
+            0 => ExitKind::Success,
+            255 => ExitKind::SshError,
+            code => ExitKind::RemoteFailure(code),
+        }
+    }
+
+    /// Check whether the target is reachable by running `true` on
+    /// it. The configured options are used, including the configured
+    /// BatchMode setting; if BatchMode is off (`batch_mode` is false,
+    /// or `password` or `askpass` is set) ssh may prompt. Forwards,
+    /// `no_remote_command`, `go_background`, and `force_tty` are left
+    /// out so that the check runs in the foreground and returns once
+    /// `true` exits. Returns whether the command exited successfully.
+    ///
+    /// This blocks until the child ssh process exits.
+    pub fn check_connection(&self) -> std::io::Result<bool> {
+        let params = SshParams {
+            go_background: false,
+            force_tty: false,
+            ..self.without_forwards()
+        };
+        let status = params
+            .to_command(&["true"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status()?;
+        Ok(status.success())
+    }
+
+    /// Run the full SSH command and capture its output. stdout and
+    /// stderr are captured rather than inherited, and stdin is null.
+    ///
+    /// This blocks until the child ssh process exits.
+    pub fn run_capture<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+    ) -> std::io::Result<std::process::Output> {
+        self.to_command(args).stdin(Stdio::null()).output()
+    }
+
+    /// Build the command for `self` with a ProxyCommand chaining
+    /// through `hops`, where the first hop is connected to directly.
+    fn hop_command<S: AsRef<OsStr>>(
+        &self,
+        hops: &[SshParams],
+        args: &[S],
+    ) -> Result<Vec<OsString>, SshParamsError> {
+        let mut params = self.clone();
+        if let Some((last, rest)) = hops.split_last() {
+            if self.proxy_command.is_some() {
+                return Err(SshParamsError::ProxyCommandWithHops);
+            }
+            // Percent tokens in the hop's own options (including its
+            // ProxyCommand) are escaped so that they are expanded by
+            // the hop's ssh rather than by this one.
+            let mut proxy: Vec<String> = last
+                .hop_command::<&str>(rest, &[])?
+                .iter()
+                .map(|arg| arg.to_string_lossy().replace('%', "%%"))
+                .collect();
+            match &last.jump_via_netcat {
+                Some(netcat) => proxy.extend_from_slice(&[
+                    netcat.replace('%', "%%"),
+                    "%h".to_string(),
+                    "%p".to_string(),
+                ]),
+                None => {
+                    let hop_target = proxy.len() - 1;
+                    proxy.splice(
+                        hop_target..hop_target,
+                        vec!["-W".to_string(), "%h:%p".to_string()],
+                    );
+                }
+            }
+
+            params.proxy_command = Some(shell_join(&proxy));
+        }
+        Ok(params.command(args))
+    }
+
+    /// Create a command that connects to `self` through a chain of
+    /// jump hosts, running `final_args` on the final host. The first
+    /// of `hops` is connected to directly, and each subsequent hop
+    /// (and finally `self`) is reached by a nested ProxyCommand using
+    /// "ssh -W", or netcat for hops with `jump_via_netcat` set. Unlike
+    /// "-J", each hop uses all of its own options, including its
+    /// identity.
+    ///
+    /// Returns [`SshParamsError::ProxyCommandWithHops`] if `self` or
+    /// any hop after the first has its own `proxy_command`, since it
+    /// would be replaced by the chain.
+    pub fn multi_hop<S: AsRef<OsStr>>(
+        &self,
+        hops: &[SshParams],
+        final_args: &[S],
+    ) -> Result<Command, SshParamsError> {
+        Ok(self.command_from_args(&self.hop_command(hops, final_args)?))
+    }
+
+    /// Create a full SSH command wrapped in `sshpass`, which enters a
+    /// password read from the environment variable `password_env` at
+    /// ssh's password prompt. BatchMode is not emitted since it would
+    /// disable password authentication.
+    ///
+    /// If `password_env` is "SSHPASS" the plain "-e" option is used;
+    /// other names are passed as "-eNAME", which requires sshpass 1.10
+    /// or later.
+    ///
+    /// Password authentication in automation is insecure: prefer key
+    /// authentication wherever possible, and note that environment
+    /// variables may be visible to other processes of the same user.
+    pub fn sshpass_command<S: AsRef<OsStr>>(
+        &self,
+        password_env: &str,
+        args: &[S],
+    ) -> Vec<OsString> {
+        let env_flag = if password_env == "SSHPASS" {
+            "-e".to_string()
+        } else {
+            format!("-e{}", password_env)
+        };
+        let params = SshParams {
+            batch_mode: false,
+            ..self.clone()
+        };
+        let mut output: Vec<OsString> = vec!["sshpass".into(), env_flag.into()];
+        output.extend(params.command(args));
+        output
+    }
+
+    /// Create a command that runs `args` on the remote host with
+    /// `sudo`, optionally as `sudo_user` ("-u" option). Each of
+    /// `args` is shell-quoted so that it reaches sudo unchanged. A
+    /// TTY is always forced so that sudo can prompt for a password.
+    pub fn sudo_command<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+        sudo_user: Option<&str>,
+    ) -> Command {
+        let mut remote = vec![OsString::from("sudo")];
+        if let Some(user) = sudo_user {
+            remote.extend_from_slice(&["-u".into(), user.into()]);
+        }
+        remote.push("--".into());
+        remote.extend(args.iter().map(|arg| arg.into()));
+
+        let params = SshParams {
+            force_tty: true,
+            ..self.clone()
+        };
+        params.to_command(&[shell_join(&remote)])
+    }
+
+    /// Create a `mosh` command line that connects to the target and
+    /// runs `args`. mosh uses ssh only to start the session, so the
+    /// SSH options (identity, port, and so on) are passed as a single
+    /// shell-quoted "--ssh=..." value. Session mode flags ("-f", "-t",
+    /// "-N") are left out since mosh manages the session itself.
+    pub fn mosh_command<S: AsRef<OsStr>>(&self, args: &[S]) -> Vec<OsString> {
+        let ssh: Vec<OsString> = self
+            .command_annotated::<&str>(&[])
+            .into_iter()
+            .filter(|(_, source)| {
+                !matches!(source, ArgSource::Mode | ArgSource::Target)
+            })
+            .map(|(arg, _)| arg)
+            .collect();
+
+        let mut output: Vec<OsString> = vec![
+            "mosh".into(),
+            format!("--ssh={}", shell_join(&ssh)).into(),
+            self.target().into(),
+        ];
+        if !args.is_empty() {
+            output.push("--".into());
+            output.extend(args.iter().map(|arg| arg.into()));
+        }
+        output
+    }
+
+    /// Create a command that runs `remote_args` on the remote host
+    /// with the contents of the local file `local` as its stdin. For
+    /// example, `&["sh", "-c", "cat > /dest"]` uploads a single file
+    /// over a plain ssh channel. Returns an error if `local` cannot be
+    /// opened.
+    pub fn pipe_file_command<S: AsRef<OsStr>>(
+        &self,
+        local: &Path,
+        remote_args: &[S],
+    ) -> io::Result<Command> {
+        let file = std::fs::File::open(local)?;
+        let mut cmd = self.to_command(remote_args);
+        cmd.stdin(file);
+        Ok(cmd)
+    }
+
+    /// Create a command that runs `args` on the remote host under
+    /// `stdbuf -oL -eL`, so that the remote program's stdout and
+    /// stderr are line-buffered and output arrives promptly instead
+    /// of in large chunks. Each of `args` is shell-quoted. Note that
+    /// stdbuf only affects programs that use C stdio buffering.
+    pub fn unbuffered_command<S: AsRef<OsStr>>(&self, args: &[S]) -> Command {
+        let mut remote: Vec<OsString> =
+            vec!["stdbuf".into(), "-oL".into(), "-eL".into()];
+        remote.extend(args.iter().map(|arg| arg.into()));
+        self.to_command(&[shell_join(&remote)])
+    }
+
+    /// Create a command that runs `args` on the remote host with the
+    /// environment variables `env` set via an `env KEY=VALUE ...`
+    /// prefix. Values and `args` are shell-quoted. This is an
+    /// alternative to `set_env` for servers that do not accept
+    /// environment variables through `AcceptEnv`.
+    ///
+    /// Each key must be a valid environment variable name, and `args`
+    /// must not be empty (a bare `env` just prints the environment).
+    pub fn command_with_remote_env<S: AsRef<OsStr>>(
+        &self,
+        env: &[(&str, &str)],
+        args: &[S],
+    ) -> Result<Command, SshParamsError> {
+        if args.is_empty() {
+            return Err(SshParamsError::MissingRemoteCommand);
+        }
+        let mut remote = vec!["env".to_string()];
+        for (key, value) in env {
+            if !is_valid_env_name(key) {
+                return Err(SshParamsError::InvalidRemoteEnv(key.to_string()));
+            }
+            remote.push(format!("{}={}", key, shell_quote(value.as_ref())));
+        }
+        remote.push(shell_join(args));
+        Ok(self.to_command(&[remote.join(" ")]))
+    }
+
+    /// Create an `ssh-copy-id` command that installs the public key
+    /// `pubkey` on the target, for onboarding a host to key
+    /// authentication. The port, user, and host key checking settings
+    /// (including `known_hosts_policy` and `known_hosts_files`) are
+    /// reused.
+    pub fn copy_id_command(&self, pubkey: &Path) -> Vec<OsString> {
+        let mut output: Vec<OsString> =
+            vec!["ssh-copy-id".into(), "-i".into(), pubkey.into()];
+        if self.address.port.is_some() {
+            let (flag, port) = self.address.port_flag(Transport::Ssh);
+            output.extend_from_slice(&[flag.into(), port.into()]);
+        }
+        for (name, value) in self.host_key_options() {
+            output.push(format!("-o{}={}", name, value).into());
+        }
+        output.push(self.target().into());
+        output
+    }
+
+    /// Render the full SSH command as a shell command string that is
+    /// safe to log: the file name of each identity is replaced with
+    /// "***" so that key names are not revealed, as is any remote
+    /// argument equal to `password`. Flags and the target are left
+    /// visible.
+    pub fn command_string_redacted<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+    ) -> String {
+        let mut command = self.command(args);
+        // Only look at options, not at the remote command.
+        let target_index = self.command::<&str>(&[]).len() - 1;
+        for i in 1..target_index {
+            if command[i - 1] == "-i" {
+                let identity = Path::new(&command[i]);
+                command[i] = identity.with_file_name("***").into();
+            }
+        }
+        if let Some(password) = self.password.as_deref() {
+            for arg in &mut command[target_index + 1..] {
+                if arg == password {
+                    *arg = "***".into();
+                }
+            }
+        }
+        shell_join(&command)
+    }
+
+    /// Render an ssh_config "Host" block for `host_alias` with these
+    /// parameters, for saving a connection to "~/.ssh/config". Only
+    /// options that are set produce a line. Settings that only apply
+    /// to a single invocation, such as `batch_mode`, `program`, the
+    /// "-f", "-t", and "-N" flags, and `login_shell`, are not
+    /// included.
+    ///
+    /// Returns [`ConfigError::InvalidValue`] if `host_alias` or any
+    /// value contains a newline or other control character, since it
+    /// would end the line and could inject other directives.
+    pub fn to_ssh_config(
+        &self,
+        host_alias: &str,
+    ) -> Result<String, ConfigError> {
+        let mut entries: Vec<(&str, String)> = Vec::new();
+        if !self.ignore_unknown.is_empty() {
+            entries.push(("IgnoreUnknown", self.ignore_unknown.join(",")));
+        }
+        entries.push(("HostName", self.address.host.clone()));
+        if let Some(tag) = &self.tag {
+            entries.push(("Tag", config_quote(tag)));
+        }
+        if let Some(port) = self.address.port {
+            entries.push(("Port", port.to_string()));
+        }
+        if let Some(user) = &self.user {
+            entries.push(("User", config_quote(user)));
+        }
+        for identity in self.identities() {
+            entries.push((
+                "IdentityFile",
+                config_quote(&identity.to_string_lossy()),
+            ));
+        }
+        if let Some(identities_only) = self.effective_identities_only() {
+            entries.push(("IdentitiesOnly", yes_no(identities_only).into()));
+        }
+        match &self.forward_agent {
+            None => {}
+            Some(ForwardAgent::No) => {
+                entries.push(("ForwardAgent", "no".into()))
+            }
+            Some(ForwardAgent::Yes) => {
+                entries.push(("ForwardAgent", "yes".into()))
+            }
+            Some(ForwardAgent::Socket(path)) => entries
+                .push(("ForwardAgent", config_quote(&path.to_string_lossy()))),
+        }
+        entries.extend(self.authentication_options());
+        entries.extend(self.host_key_options());
+        let algorithms = [
+            ("HostKeyAlgorithms", &self.host_key_algorithms),
+            ("Ciphers", &self.ciphers),
+            ("MACs", &self.macs),
+            ("KexAlgorithms", &self.kex_algorithms),
+        ];
+        for (name, value) in algorithms.iter() {
+            if let Some(value) = value {
+                entries.push((name, value.clone()));
+            }
+        }
+        if self.address_family != AddressFamily::Any {
+            entries.push(("AddressFamily", self.address_family.to_string()));
+        }
+        if let Some(bind_address) = &self.bind_address {
+            entries.push(("BindAddress", bind_address.clone()));
+        }
+        for forward in &self.forwards {
+            entries.push(forward.config_entry());
+        }
+        if let Some(gateway_ports) = self.gateway_ports {
+            entries.push(("GatewayPorts", gateway_ports.to_string()));
+        }
+        if let Some(master) = self.control_master {
+            entries.push(("ControlMaster", master.to_string()));
+        }
+        if let Some(path) = &self.control_path {
+            entries
+                .push(("ControlPath", config_quote(&path.to_string_lossy())));
+        }
+        if let Some(persist) = &self.control_persist {
+            entries.push(("ControlPersist", persist.clone()));
+        }
+        if let Some(level) = self.log_level {
+            entries.push(("LogLevel", level.to_string()));
+        }
+        if let Some(interval) = self.server_alive_interval {
+            entries
+                .push(("ServerAliveInterval", ceil_secs(interval).to_string()));
+        }
+        if let Some(count) = self.server_alive_count_max {
+            entries.push(("ServerAliveCountMax", count.to_string()));
+        }
+        if let Some(keep_alive) = self.tcp_keep_alive {
+            entries.push(("TCPKeepAlive", yes_no(keep_alive).into()));
+        }
+        if let Some(attempts) = self.connection_attempts {
+            entries.push(("ConnectionAttempts", attempts.to_string()));
+        }
+        if let Some(timeout) = self.connect_timeout {
+            entries.push(("ConnectTimeout", ceil_secs(timeout).to_string()));
+        }
+        for name in &self.send_env {
+            entries.push(("SendEnv", name.clone()));
+        }
+        for (name, value) in &self.set_env {
+            entries
+                .push(("SetEnv", format!("{}={}", name, config_quote(value))));
+        }
+        if let Some(proxy_command) = &self.proxy_command {
+            entries.push(("ProxyCommand", proxy_command.clone()));
+        }
+
+        let invalid = |keyword: &str, value: &str| {
+            if value.contains(|c: char| c.is_control()) {
+                Err(ConfigError::InvalidValue {
+                    keyword: keyword.to_string(),
+                    value: value.to_string(),
+                })
+            } else {
+                Ok(())
+            }
+        };
+        invalid("Host", host_alias)?;
+        let mut output = format!("Host {}\n", host_alias);
+        for (name, value) in entries {
+            invalid(name, &value)?;
+            output.push_str(&format!("    {} {}\n", name, value));
+        }
+        Ok(output)
+    }
+
+    /// Parse the text of a single ssh_config "Host" stanza, such as
+    /// the output of [`SshParams::to_ssh_config`]. The "HostName",
+    /// "Port", "User", "IdentityFile", and "StrictHostKeyChecking"
+    /// keywords are understood; keywords are case-insensitive and
+    /// unknown keywords are ignored. If there is no "HostName", the
+    /// "Host" pattern is used as the host, as ssh does; in that case it
+    /// must be a single pattern without wildcards. The host is checked
+    /// the same way as when parsing an [`Address`] with [`str::parse`].
+    pub fn from_ssh_config(stanza: &str) -> Result<SshParams, ConfigError> {
+        let mut params = SshParams::default();
+        let mut host_alias: Option<String> = None;
+        let mut host_name: Option<String> = None;
+        for line in stanza.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // Keywords are separated from values by whitespace or "=".
+            let split = line
+                .find(|c: char| c.is_whitespace() || c == '=')
+                .unwrap_or(line.len());
+            let (keyword, value) = line.split_at(split);
+            let value = value
+                .trim_start()
+                .strip_prefix('=')
+                .unwrap_or_else(|| value.trim_start())
+                .trim();
+            if value.is_empty() {
+                return Err(ConfigError::MissingValue(keyword.to_string()));
+            }
+            let value = config_unquote(value);
+            let invalid = || ConfigError::InvalidValue {
+                keyword: keyword.to_string(),
+                value: value.clone(),
+            };
+
+            match keyword.to_ascii_lowercase().as_str() {
+                "host" => {
+                    if host_alias.is_some() {
+                        return Err(ConfigError::MultipleHosts);
+                    }
+                    host_alias = Some(value);
+                }
+                "hostname" => host_name = Some(value),
+                "port" => {
+                    params.address.port =
+                        Some(value.parse().map_err(|_| invalid())?);
+                }
+                "user" => params.user = Some(value),
+                "identityfile" => {
+                    if params.identity.is_none() {
+                        params.identity = Some(value.into());
+                    } else {
+                        params.extra_identities.push(value.into());
+                    }
+                }
+                "stricthostkeychecking" => {
+                    params.strict_host_key_checking =
+                        match value.to_ascii_lowercase().as_str() {
+                            "yes" | "ask" | "accept-new" => true,
+                            "no" | "off" => false,
+                            _ => return Err(invalid()),
+                        };
+                }
+                _ => {}
+            }
+        }
+        let (keyword, host) = match (host_name, host_alias) {
+            (Some(host), _) => ("HostName", host),
+            // A "Host" line can hold several patterns or wildcards,
+            // neither of which names a single host to connect to.
+            (None, Some(host)) if host.contains(&['*', '?', '!'][..]) => {
+                return Err(ConfigError::InvalidValue {
+                    keyword: "Host".to_string(),
+                    value: host,
+                })
+            }
+            (None, Some(host)) => ("Host", host),
+            (None, None) => return Err(ConfigError::MissingHostName),
+        };
+        if check_host(&host).is_err() {
+            return Err(ConfigError::InvalidValue {
+                keyword: keyword.to_string(),
+                value: host,
+            });
+        }
+        params.address.host = host;
+        Ok(params)
+    }
+
+    /// Render a "spawn ssh ..." line for a Tcl/expect script, with each
+    /// argument quoted using Tcl's rules (braces where possible).
+    pub fn expect_spawn_line<S: AsRef<OsStr>>(&self, args: &[S]) -> String {
+        let mut words = vec!["spawn".to_string()];
+        words.extend(
+            self.command(args)
+                .iter()
+                .map(|arg| tcl_quote(&arg.to_string_lossy())),
+        );
+        words.join(" ")
+    }
+
+    /// Create a command that runs the SSH command under `script` so
+    /// that the whole session is recorded to `logfile`. The SSH
+    /// command is shell-quoted and passed to `script -q -c`.
+    ///
+    /// Only available on Linux, since this uses the util-linux
+    /// `script` syntax; the BSD and macOS `script` has no "-c" option.
+    #[cfg(target_os = "linux")]
+    pub fn logged_command<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+        logfile: &Path,
+    ) -> Command {
+        let mut cmd = Command::new("script");
+        cmd.args(["-q", "-c"])
+            .arg(shell_join(&self.command(args)))
+            .arg(logfile);
+        self.apply_askpass_env(&mut cmd);
+        cmd
+    }
+}
+
+impl Display for SshParams {
+    /// Format a compact summary of the parameters such as
+    /// "me@host:2222 (identity=/key, strict=false)". Unset options
+    /// are omitted. This is not a runnable command; see
+    /// [`SshParams::command`] for that.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        write!(f, "{}", self.address)?;
+
+        let mut details = Vec::new();
+        for identity in self.identities() {
+            details.push(format!("identity={}", identity.display()));
+        }
+        if !self.strict_host_key_checking {
+            details.push("strict=false".to_string());
+        }
+        if !details.is_empty() {
+            write!(f, " ({})", details.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_test::{
+        assert_de_tokens, assert_de_tokens_error, assert_ser_tokens,
+        assert_tokens, Configure, Readable, Token,
+    };
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+
+    /// Held by tests that read or modify `HOME`.
+    static HOME_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn test_address_parse() {
+        assert_eq!("a".parse(), Ok(Address::from_host("a")));
+        assert_eq!("a:1234".parse(), Ok(Address::new("a", 1234)));
+        assert_eq!("".parse::<Address>(), Err(AddressError::InvalidFormat));
+        assert_eq!("a:b".parse::<Address>(), Err(AddressError::InvalidPort));
+        assert_eq!(
+            "a:1234:5678".parse::<Address>(),
+            Err(AddressError::InvalidFormat)
+        );
+    }
+
+    #[test]
+    fn test_address_ord() {
+        let mut addrs = vec![
+            Address::new("b", 22),
+            Address::new("a", 2222),
+            Address::from_host("b"),
+            Address::new("a", 22),
+            Address::from_host("a"),
+            Address::new("a", 22),
+        ];
+        addrs.sort();
+        addrs.dedup();
+        assert_eq!(
+            addrs,
+            vec![
+                Address::from_host("a"),
+                Address::new("a", 22),
+                Address::new("a", 2222),
+                Address::from_host("b"),
+                Address::new("b", 22),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_address_parse_lenient() {
+        let expected = Address::new("host", 22);
+        assert_eq!(
+            Address::parse_lenient("ssh://host:22/"),
+            Ok(expected.clone())
+        );
+        assert_eq!(Address::parse_lenient("host:22/"), Ok(expected.clone()));
+        assert_eq!(Address::parse_lenient("host:22"), Ok(expected));
+        assert_eq!(
+            Address::parse_lenient("ssh://[::1]:2222/"),
+            Ok(Address::new("::1", 2222))
+        );
+        assert_eq!(
+            Address::parse_lenient("ssh://"),
+            Err(AddressError::InvalidFormat)
+        );
+    }
+
+    #[test]
+    fn test_address_is_default_port() {
+        let address = Address::from_host("host");
+        assert!(address.is_default_port());
+        assert_eq!(address.to_string(), "host");
+        assert_eq!(format!("{:#}", address), "host");
+
+        let address = Address::new("host", 22);
+        assert!(address.is_default_port());
+        assert_eq!(address.to_string(), "host:22");
+        assert_eq!(format!("{:#}", address), "host");
+
+        let address = Address::new("::1", 2222);
+        assert!(!address.is_default_port());
+        assert_eq!(address.to_string(), "[::1]:2222");
+        assert_eq!(format!("{:#}", address), "[::1]:2222");
+    }
+
+    #[test]
+    fn test_address_host_port() {
+        assert_eq!(Address::from_host("host").host_port(), "host:22");
+        assert_eq!(Address::new("host", 2222).host_port(), "host:2222");
+        assert_eq!(Address::from_host("::1").host_port(), "[::1]:22");
+        assert_eq!(Address::new("fe80::1", 2222).host_port(), "[fe80::1]:2222");
+    }
+
+    #[test]
+    fn test_address_parts() {
+        let address = Address::from_host("host");
+        assert_eq!(address.as_parts(), ("host", 22));
+        assert_eq!(address.into_parts(), ("host".to_string(), 22));
+
+        let address = Address::new("::1", 2222);
+        assert_eq!(address.as_parts(), ("::1", 2222));
+        assert_eq!(address.into_parts(), ("::1".to_string(), 2222));
+    }
+
+    #[test]
+    fn test_address_from() {
+        let addr: SocketAddr = "10.0.0.1:2222".parse().unwrap();
+        assert_eq!(Address::from(addr), Address::new("10.0.0.1", 2222));
+
+        let addr: SocketAddr = "[fe80::1]:22".parse().unwrap();
+        let address = Address::from(addr);
+        assert_eq!(address, Address::new("fe80::1", 22));
+        assert_eq!(address.to_string(), "[fe80::1]:22");
+
+        // The scope id is kept.
+        let addr: SocketAddr = "[fe80::1%2]:22".parse().unwrap();
+        let address = Address::from(addr);
+        assert_eq!(address, Address::new("fe80::1%2", 22));
+        assert_eq!(address.to_string(), "[fe80::1%2]:22");
+        assert_eq!(address.to_string().parse(), Ok(address));
+
+        assert_eq!(
+            Address::from(("host".to_string(), 2222)),
+            Address::new("host", 2222)
+        );
+        assert_eq!(
+            Address::try_from("host:2222"),
+            Ok(Address::new("host", 2222))
+        );
+        assert_eq!(Address::try_from(""), Err(AddressError::InvalidFormat));
+    }
+
+    #[test]
+    fn test_address_normalized() {
+        assert_eq!(
+            Address::new("Host.Example.COM.", 2222).normalized(),
+            Address::new("host.example.com", 2222)
+        );
+        assert_eq!(
+            Address::from_host("host").normalized(),
+            Address::from_host("host")
+        );
+        assert_eq!(
+            Address::from_host("10.0.0.1").normalized(),
+            Address::from_host("10.0.0.1")
+        );
+        assert_eq!(
+            Address::from_host("fe80::1%ETH0").normalized(),
+            Address::from_host("fe80::1%ETH0")
+        );
+    }
+
+    #[test]
+    fn test_address_resolve() {
+        assert_eq!(
+            Address::from_host("127.0.0.1").resolve().unwrap(),
+            vec!["127.0.0.1:22".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(
+            Address::new("::1", 2222).resolve().unwrap(),
+            vec!["[::1]:2222".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_address_parse_service() {
+        let services = "\
+# comment
+ssh\t\t22/tcp\t\t# SSH Remote Login Protocol
+domain\t\t53/udp
+http\t\t80/tcp\t\twww # WorldWideWeb HTTP
+";
+        assert_eq!(find_service_port(services, "ssh"), Some(22));
+        assert_eq!(find_service_port(services, "www"), Some(80));
+        assert_eq!(find_service_port(services, "domain"), None);
+        assert_eq!(find_service_port(services, "bogus"), None);
+
+        assert_eq!(
+            Address::parse_service("host:1234"),
+            Ok(Address::new("host", 1234))
+        );
+        assert_eq!(
+            Address::parse_service("host:bogus"),
+            Err(AddressError::InvalidPort)
+        );
+        if Path::new("/etc/services").exists() {
+            assert_eq!(
+                Address::parse_service("host:ssh"),
+                Ok(Address::new("host", 22))
+            );
+        }
+    }
+
+    #[test]
+    fn test_address_effective_port() {
+        let addr = Address::from_host("abc");
+        assert_eq!(addr.effective_port(), DEFAULT_SSH_PORT);
+        assert_eq!(addr.with_default_port(), Address::new("abc", 22));
+
+        let addr = Address::new("abc", 2222);
+        assert_eq!(addr.effective_port(), 2222);
+        assert_eq!(addr.clone().with_default_port(), addr);
+    }
+
+    /// Check that every place that falls back to a default port uses
+    /// `DEFAULT_SSH_PORT`, so that they cannot diverge.
+    #[test]
+    fn test_default_port_consistency() {
+        let default = DEFAULT_SSH_PORT.to_string();
+        let unset = Address::from_host("abc");
+        let explicit = Address::new("abc", DEFAULT_SSH_PORT);
+
+        for addr in &[&unset, &explicit] {
+            assert_eq!(addr.effective_port(), DEFAULT_SSH_PORT);
+            assert!(addr.is_default_port());
+            assert_eq!(addr.port_flag(Transport::Ssh).1, default);
+            assert_eq!(addr.keyscan_command(), vec!["ssh-keyscan", "abc"]);
+            assert_eq!(format!("{:#}", addr), "abc");
+        }
+        assert_eq!(unset.clone().with_default_port(), explicit);
+
+        let params = SshParams {
+            address: unset,
+            control_path: Some("%p".into()),
+            ..Default::default()
+        };
+        assert_eq!(params.expanded_control_path(), Some(default.into()));
+    }
+
+    #[test]
+    fn test_address_parse_host() {
+        assert_eq!(
+            "-oProxyCommand=evil".parse::<Address>(),
+            Err(AddressError::InvalidHost)
+        );
+        assert_eq!(
+            "-oProxyCommand=evil:22".parse::<Address>(),
+            Err(AddressError::InvalidHost)
+        );
+        assert_eq!("a b".parse::<Address>(), Err(AddressError::InvalidHost));
+        assert_eq!("a\tb".parse::<Address>(), Err(AddressError::InvalidHost));
+        assert_eq!("a\n".parse::<Address>(), Err(AddressError::InvalidHost));
+        assert_eq!(
+            "[-x]:22".parse::<Address>(),
+            Err(AddressError::InvalidHost)
+        );
+
+        assert_eq!(
+            "my-host.example.com".parse(),
+            Ok(Address::from_host("my-host.example.com"))
+        );
+        assert_eq!("10.0.0.1:22".parse(), Ok(Address::new("10.0.0.1", 22)));
+        assert_eq!(
+            "[fe80::1%eth0]:22".parse(),
+            Ok(Address::new("fe80::1%eth0", 22))
+        );
+    }
+
+    #[test]
+    fn test_address_parse_ipv6() {
+        assert_eq!("::1".parse(), Ok(Address::from_host("::1")));
+        assert_eq!("[::1]".parse(), Ok(Address::from_host("::1")));
+        assert_eq!("[::1]:2222".parse(), Ok(Address::new("::1", 2222)));
+        assert_eq!(
+            "[]:22".parse::<Address>(),
+            Err(AddressError::InvalidFormat)
+        );
+        assert_eq!("[::1".parse::<Address>(), Err(AddressError::InvalidFormat));
+        assert_eq!(
+            "[::1]x".parse::<Address>(),
+            Err(AddressError::InvalidFormat)
+        );
+        assert_eq!(
+            "[::1]:x".parse::<Address>(),
+            Err(AddressError::InvalidPort)
+        );
+
+        assert_eq!(Address::from_host("::1").to_string(), "[::1]");
+        assert_eq!(Address::new("::1", 2222).to_string(), "[::1]:2222");
+    }
+
+    #[test]
+    fn test_address_display() {
+        let addr = Address::from_host("abc");
+        assert_eq!(format!("{}", addr), "abc");
+        let addr = Address::new("abc", 123);
+        assert_eq!(format!("{}", addr), "abc:123");
+    }
+
+    #[test]
+    fn test_address_de_map() {
+        assert_de_tokens(
+            &Address::new("x", 2222).readable(),
+            &[
+                Token::Map { len: Some(2) },
+                Token::Str("host"),
+                Token::Str("x"),
+                Token::Str("port"),
+                Token::U16(2222),
+                Token::MapEnd,
+            ],
+        );
+        assert_de_tokens(
+            &Address::from_host("x").readable(),
+            &[
+                Token::Map { len: Some(1) },
+                Token::Str("host"),
+                Token::Str("x"),
+                Token::MapEnd,
+            ],
+        );
+        assert_de_tokens(
+            &Address::new("x", 2222).readable(),
+            &[Token::Str("x:2222")],
+        );
+
+        // "hostname" is an alias for "host".
+        assert_de_tokens(
+            &Address::new("x", 2222).readable(),
+            &[
+                Token::Map { len: Some(2) },
+                Token::Str("hostname"),
+                Token::Str("x"),
+                Token::Str("port"),
+                Token::U16(2222),
+                Token::MapEnd,
+            ],
+        );
+        assert_de_tokens_error::<Readable<Address>>(
+            &[
+                Token::Map { len: Some(2) },
+                Token::Str("host"),
+                Token::Str("x"),
+                Token::Str("hostname"),
+            ],
+            "duplicate field `host`",
+        );
+
+        // Serialization always uses the string form.
+        assert_ser_tokens(
+            &Address::new("x", 2222).readable(),
+            &[Token::Str("x:2222")],
+        );
+        assert_de_tokens_error::<Readable<Address>>(
+            &[
+                Token::Map { len: Some(1) },
+                Token::Str("port"),
+                Token::U16(22),
+                Token::MapEnd,
+            ],
+            "missing field `host`",
+        );
+
+        // Compact formats, which cannot tell a map from a string, use
+        // the string form only.
+        assert_tokens(
+            &Address::new("x", 2222).compact(),
+            &[Token::Str("x:2222")],
+        );
+        assert_tokens(
+            &Address::new("x", 2222).readable(),
+            &[Token::Str("x:2222")],
+        );
+    }
+
+    #[test]
+    fn test_address_port_flag() {
+        let addr = Address::new("abc", 2222);
+        assert_eq!(addr.port_flag(Transport::Ssh), ("-p", "2222".into()));
+        assert_eq!(addr.port_flag(Transport::Scp), ("-P", "2222".into()));
+        assert_eq!(addr.port_flag(Transport::Sftp), ("-P", "2222".into()));
+
+        let addr = Address::from_host("abc");
+        assert_eq!(addr.port_flag(Transport::Ssh), ("-p", "22".into()));
+    }
+
+    #[test]
+    fn test_address_keyscan_command() {
+        assert_eq!(
+            Address::from_host("host").keyscan_command(),
+            vec!["ssh-keyscan", "host"]
+        );
+        assert_eq!(
+            Address::new("host", 22).keyscan_command(),
+            vec!["ssh-keyscan", "host"]
+        );
+        assert_eq!(
+            Address::new("::1", 2222).keyscan_command(),
+            vec!["ssh-keyscan", "-p", "2222", "::1"]
+        );
+    }
+
+    #[test]
+    fn test_address_tokens() {
+        assert_tokens(
+            &Address::from_host("abc").readable(),
+            &[Token::Str("abc")],
+        );
+        assert_tokens(
+            &Address::new("abc", 123).readable(),
+            &[Token::Str("abc:123")],
+        );
+    }
+
+    #[test]
+    fn test_command() {
+        let target = SshParams {
+            address: "localhost:9222".parse().unwrap(),
+            identity: Some(Path::new("/myIdentity").to_path_buf()),
+            user: Some("me".to_string()),
+            strict_host_key_checking: false,
+            ..Default::default()
+        };
+        let cmd = target.command(&["arg1", "arg2"]);
+        assert_eq!(
+            cmd,
+            vec![
+                "ssh",
+                "-oStrictHostKeyChecking=no",
+                "-oUserKnownHostsFile=/dev/null",
+                "-oBatchMode=yes",
+                "-i",
+                "/myIdentity",
+                "-p",
+                "9222",
                 "me@localhost",
                 "arg1",
                 "arg2"
             ]
         );
     }
+
+    #[test]
+    fn test_command_multiple_identities() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            identity: Some(Path::new("/key1").to_path_buf()),
+            extra_identities: vec![Path::new("/key2").to_path_buf()],
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command(&["true"]),
+            vec![
+                "ssh",
+                "-oBatchMode=yes",
+                "-i",
+                "/key1",
+                "-i",
+                "/key2",
+                "-oIdentitiesOnly=yes",
+                "host",
+                "true"
+            ]
+        );
+
+        // An explicit false overrides the automatic behavior.
+        let params = SshParams {
+            identities_only: Some(false),
+            ..params
+        };
+        assert!(params
+            .command::<&str>(&[])
+            .contains(&"-oIdentitiesOnly=no".into()));
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("abc".as_ref()), "abc");
+        assert_eq!(shell_quote("/a/b-c.txt".as_ref()), "/a/b-c.txt");
+        assert_eq!(shell_quote("".as_ref()), "''");
+        assert_eq!(shell_quote("a b".as_ref()), "'a b'");
+        assert_eq!(shell_quote("it's".as_ref()), "'it'\\''s'");
+        assert_eq!(shell_quote("*".as_ref()), "'*'");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_logged_command() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ..Default::default()
+        };
+        let cmd = params.logged_command(&["echo", "a b"], Path::new("/log"));
+        assert_eq!(cmd.get_program(), "script");
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            vec!["-q", "-c", "ssh -oBatchMode=yes host echo 'a b'", "/log"]
+        );
+    }
+
+    #[test]
+    fn test_validate_background_tty() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            go_background: true,
+            force_tty: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            params.validate::<&str>(&[]),
+            Err(SshParamsError::BackgroundTtyWithoutCommand)
+        );
+        assert_eq!(params.validate(&["top"]), Ok(()));
+        assert_eq!(
+            params.command(&["top"]),
+            vec!["ssh", "-oBatchMode=yes", "-f", "-t", "host", "top"]
+        );
+
+        let params = SshParams {
+            force_tty: false,
+            ..params
+        };
+        assert_eq!(params.validate::<&str>(&[]), Ok(()));
+    }
+
+    #[test]
+    fn test_params_tokens() {
+        let params = SshParams {
+            address: Address::new("host", 2222),
+            identity: Some(Path::new("/key1").to_path_buf()),
+            extra_identities: vec![Path::new("/key2").to_path_buf()],
+            identities_only: Some(false),
+            user: Some("me".to_string()),
+            strict_host_key_checking: false,
+            go_background: true,
+            force_tty: true,
+            ..Default::default()
+        };
+        assert_tokens(
+            &params.readable(),
+            &[
+                Token::Struct {
+                    name: "SshParams",
+                    len: 8,
+                },
+                Token::Str("address"),
+                Token::Str("host:2222"),
+                Token::Str("identity"),
+                Token::Some,
+                Token::Str("/key1"),
+                Token::Str("extra_identities"),
+                Token::Seq { len: Some(1) },
+                Token::Str("/key2"),
+                Token::SeqEnd,
+                Token::Str("identities_only"),
+                Token::Some,
+                Token::Bool(false),
+                Token::Str("user"),
+                Token::Some,
+                Token::Str("me"),
+                Token::Str("strict_host_key_checking"),
+                Token::Bool(false),
+                Token::Str("go_background"),
+                Token::Bool(true),
+                Token::Str("force_tty"),
+                Token::Bool(true),
+                Token::StructEnd,
+            ],
+        );
+
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ..Default::default()
+        };
+        assert_tokens(
+            &params.readable(),
+            &[
+                Token::Struct {
+                    name: "SshParams",
+                    len: 1,
+                },
+                Token::Str("address"),
+                Token::Str("host"),
+                Token::StructEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_params_display() {
+        let params = SshParams {
+            address: Address::new("host", 2222),
+            identity: Some(Path::new("/key").to_path_buf()),
+            user: Some("me".to_string()),
+            strict_host_key_checking: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            params.to_string(),
+            "me@host:2222 (identity=/key, strict=false)"
+        );
+
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ..Default::default()
+        };
+        assert_eq!(params.to_string(), "host");
+        assert_eq!(SshParams::default().to_string(), "");
+    }
+
+    #[test]
+    fn test_command_forwards() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            forwards: vec![
+                Forward::local("8080", "localhost:80"),
+                Forward::remote("127.0.0.1:9000", "localhost:9000"),
+                Forward::dynamic("1080"),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec![
+                "ssh",
+                "-oBatchMode=yes",
+                "-L",
+                "8080:localhost:80",
+                "-R",
+                "127.0.0.1:9000:localhost:9000",
+                "-D",
+                "1080",
+                "host"
+            ]
+        );
+
+        let params = SshParams {
+            forwards: vec![Forward::local("8080", "localhost:80")],
+            forwards_as_options: true,
+            ..params
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec![
+                "ssh",
+                "-oBatchMode=yes",
+                "-oLocalForward=8080 localhost:80",
+                "host"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_connection() {
+        // Use stub programs in place of ssh.
+        let params = SshParams {
+            address: Address::from_host("host"),
+            program: "true".into(),
+            ..Default::default()
+        };
+        assert!(params.check_connection().unwrap());
+
+        let params = SshParams {
+            program: "false".into(),
+            ..params
+        };
+        assert!(!params.check_connection().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_connection_tunnel_profile() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir()
+            .join(format!("nbssh-test-check-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Use a stub in place of ssh that succeeds only if it would run
+        // "true" in the foreground.
+        let stub = dir.join("ssh");
+        std::fs::write(
+            &stub,
+            "#!/bin/sh\nfor arg; do case $arg in -N|-f|-t|-L) exit 1;; esac; \
+             last=$arg; done\ntest \"$last\" = true\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&stub, std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+
+        let params = SshParams {
+            address: Address::from_host("host"),
+            program: stub,
+            no_remote_command: true,
+            go_background: true,
+            force_tty: true,
+            forwards: vec![Forward::local("8080", "localhost:80")],
+            ..Default::default()
+        };
+        assert!(params.check_connection().unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_spawn_tokio() {
+        // Use echo in place of ssh.
+        let params = SshParams {
+            address: Address::from_host("host"),
+            program: "echo".into(),
+            batch_mode: false,
+            ..Default::default()
+        };
+        let output = params.run_capture_tokio(&["hello"]).await.unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"host hello\n");
+
+        // Use true in place of ssh, since stdout is inherited.
+        let params = SshParams {
+            program: "true".into(),
+            ..params
+        };
+        let status = params.spawn_tokio(&["hello"]).unwrap().wait().await;
+        assert!(status.unwrap().success());
+    }
+
+    #[test]
+    fn test_run_capture() {
+        // Use echo in place of ssh.
+        let params = SshParams {
+            address: Address::from_host("host"),
+            program: "echo".into(),
+            ..Default::default()
+        };
+        let output = params.run_capture(&["echo", "hello"]).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"-oBatchMode=yes host echo hello\n");
+        assert!(output.stderr.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_local_hostname() {
+        let output = Command::new("uname").arg("-n").output().unwrap();
+        let expected = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(local_hostname(), expected.trim());
+        assert!(!local_hostname().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_master_socket_exists() {
+        use std::os::unix::net::UnixListener;
+
+        let dir = std::env::temp_dir()
+            .join(format!("nbssh-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let params = SshParams {
+            address: Address::new("host", 2222),
+            user: Some("me".to_string()),
+            control_path: Some(dir.join("%r@%h:%p")),
+            ..Default::default()
+        };
+        assert!(!params.master_socket_exists());
+
+        // A regular file is not a socket.
+        let socket = dir.join("me@host:2222");
+        std::fs::write(&socket, "").unwrap();
+        assert!(!params.master_socket_exists());
+        std::fs::remove_file(&socket).unwrap();
+
+        let _listener = UnixListener::bind(&socket).unwrap();
+        assert!(params.master_socket_exists());
+
+        let params = SshParams {
+            control_path: Some("none".into()),
+            ..params
+        };
+        assert!(!params.master_socket_exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_master_socket_exists_home() {
+        use std::os::unix::net::UnixListener;
+
+        let _lock = HOME_LOCK.lock().unwrap();
+        let dir = std::env::temp_dir()
+            .join(format!("nbssh-test-home-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join(".ssh")).unwrap();
+        let old_home = std::env::var_os("HOME");
+        std::env::set_var("HOME", &dir);
+
+        let params = SshParams {
+            address: Address::new("host", 2222),
+            user: Some("me".to_string()),
+            control_path: Some("~/.ssh/cm-%r@%h:%p".into()),
+            ..Default::default()
+        };
+        let socket = dir.join(".ssh/cm-me@host:2222");
+        assert_eq!(params.expanded_control_path(), Some(socket.clone()));
+        assert!(!params.master_socket_exists());
+        let _listener = UnixListener::bind(&socket).unwrap();
+        assert!(params.master_socket_exists());
+
+        match old_home {
+            Some(home) => std::env::set_var("HOME", home),
+            None => std::env::remove_var("HOME"),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_forward_agent() {
+        let mut params = SshParams {
+            address: Address::from_host("host"),
+            batch_mode: false,
+            ..Default::default()
+        };
+        for (forward_agent, arg) in [
+            (ForwardAgent::No, "-a"),
+            (ForwardAgent::Yes, "-A"),
+            (
+                ForwardAgent::Socket("$MY_AGENT".into()),
+                "-oForwardAgent=$MY_AGENT",
+            ),
+        ]
+        .iter()
+        {
+            params.forward_agent = Some(forward_agent.clone());
+            assert_eq!(params.command::<&str>(&[]), vec!["ssh", arg, "host"]);
+            assert_eq!(params.validate::<&str>(&[]), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_try_command() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            batch_mode: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            params.try_command(&["true"]),
+            Ok(vec!["ssh".into(), "host".into(), "true".into()])
+        );
+
+        let params = SshParams {
+            address: Address::default(),
+            ..params
+        };
+        assert_eq!(
+            params.try_command(&["true"]),
+            Err(SshParamsError::EmptyHost)
+        );
+
+        // A host that ssh would read as an option is rejected.
+        let params = SshParams {
+            address: Address::from_host("-oProxyCommand=touch /tmp/pwn"),
+            ..params
+        };
+        assert_eq!(
+            params.try_command(&["true"]),
+            Err(SshParamsError::InvalidHost(
+                "-oProxyCommand=touch /tmp/pwn".to_string()
+            ))
+        );
+
+        let params = SshParams {
+            address: Address::from_host("host"),
+            no_remote_command: true,
+            ..params
+        };
+        assert_eq!(
+            params.try_command(&["true"]),
+            Err(SshParamsError::UnexpectedRemoteCommand)
+        );
+        assert!(params.try_command::<&str>(&[]).is_ok());
+    }
+
+    #[test]
+    fn test_validate_identities_only() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            identities_only: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.validate::<&str>(&[]),
+            Err(SshParamsError::IdentitiesOnlyWithoutIdentity)
+        );
+
+        let params = SshParams {
+            identity: Some("/key".into()),
+            ..params
+        };
+        assert_eq!(params.validate::<&str>(&[]), Ok(()));
+
+        let params = SshParams {
+            identity: None,
+            identities_only: Some(false),
+            ..params
+        };
+        assert_eq!(params.validate::<&str>(&[]), Ok(()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_validate_agent_socket() {
+        let path = PathBuf::from("/nonexistent/agent.sock");
+        let params = SshParams {
+            address: Address::from_host("host"),
+            forward_agent: Some(ForwardAgent::Socket(path.clone())),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.validate::<&str>(&[]),
+            Err(SshParamsError::InvalidAgentSocket(path))
+        );
+
+        // A regular file is not a socket.
+        let params = SshParams {
+            forward_agent: Some(ForwardAgent::Socket("/dev/null".into())),
+            ..params
+        };
+        assert!(params.validate::<&str>(&[]).is_err());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            user: Some("me".to_string()),
+            ..Default::default()
+        };
+        let fingerprint = params.fingerprint();
+        assert_eq!(fingerprint.len(), 16);
+        assert_eq!(params.clone().fingerprint(), fingerprint);
+
+        let params = SshParams {
+            user: Some("you".to_string()),
+            ..params
+        };
+        assert_ne!(params.fingerprint(), fingerprint);
+
+        // The encoding is fixed, not derived from Debug output.
+        assert_eq!(SshParams::default().fingerprint(), "d283c20aa7fb31a1");
+
+        // The password does not affect the fingerprint.
+        let with_password = SshParams {
+            password: Some("hunter2".to_string()),
+            ..params.clone()
+        };
+        assert_eq!(with_password.fingerprint(), params.fingerprint());
+    }
+
+    #[test]
+    fn test_expand_tokens() {
+        let params = SshParams {
+            address: Address::new("host", 2222),
+            user: Some("me".to_string()),
+            identity: Some("/keys/id_%h".into()),
+            extra_identities: vec!["/keys/%r-%p".into()],
+            control_path: Some("/tmp/%h:%p-%x".into()),
+            ..Default::default()
+        };
+        let expanded = params.expand_tokens();
+        assert_eq!(expanded.identity, Some("/keys/id_host".into()));
+        assert_eq!(
+            expanded.extra_identities,
+            vec![PathBuf::from("/keys/me-2222")]
+        );
+        // Unknown tokens are left as-is.
+        assert_eq!(expanded.control_path, Some("/tmp/host:2222-%x".into()));
+        assert_eq!(expanded.address, params.address);
+    }
+
+    #[test]
+    fn test_expand_home() {
+        let _lock = HOME_LOCK.lock().unwrap();
+        let passwd = "\
+root:x:0:0:root:/root:/bin/bash
+me:x:1000:1000:Me,,,:/home/me:/bin/bash
+";
+        assert_eq!(find_passwd_home(passwd, "me"), Some("/home/me".into()));
+        assert_eq!(find_passwd_home(passwd, "bogus"), None);
+
+        let home = PathBuf::from(std::env::var_os("HOME").unwrap());
+        let params = SshParams {
+            address: Address::from_host("host"),
+            identity: Some("~/.ssh/id_ed25519".into()),
+            extra_identities: vec!["/abs/key".into()],
+            known_hosts_files: vec!["~".into()],
+            config_file: Some("~nosuchuser/config".into()),
+            ..Default::default()
+        };
+        let expanded = params.expand_home();
+        assert_eq!(expanded.identity, Some(home.join(".ssh/id_ed25519")));
+        assert_eq!(expanded.extra_identities, vec![PathBuf::from("/abs/key")]);
+        assert_eq!(expanded.known_hosts_files, vec![home]);
+        assert_eq!(expanded.config_file, Some("~nosuchuser/config".into()));
+    }
+
+    #[test]
+    fn test_params_from_target() {
+        let params = SshParams::from_target("me@host:2222").unwrap();
+        assert_eq!(params.user, Some("me".to_string()));
+        assert_eq!(params.address, Address::new("host", 2222));
+
+        let params = SshParams::from_target("host").unwrap();
+        assert_eq!(params.user, None);
+        assert_eq!(params.address, Address::from_host("host"));
+
+        assert_eq!(
+            SshParams::from_target("@host"),
+            Err(AddressError::InvalidFormat)
+        );
+        assert_eq!(
+            SshParams::from_target("me@"),
+            Err(AddressError::InvalidFormat)
+        );
+    }
+
+    #[test]
+    fn test_prefer_host_key_type() {
+        let mut params = SshParams {
+            address: Address::from_host("host"),
+            ..Default::default()
+        };
+        params.prefer_host_key_type("ed25519");
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec![
+                "ssh",
+                "-oHostKeyAlgorithms=^ssh-ed25519",
+                "-oBatchMode=yes",
+                "host"
+            ]
+        );
+
+        params.prefer_host_key_type("rsa-sha2-256");
+        assert_eq!(
+            params.host_key_algorithms,
+            Some("^rsa-sha2-256".to_string())
+        );
+    }
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(
+            sha1::sha1_hex(b""),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+        assert_eq!(
+            sha1::sha1_hex(b"abc"),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            sha1::sha1_hex(&[b'a'; 1000]),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+    }
+
+    #[test]
+    fn test_shares_control_master() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            user: Some("me".to_string()),
+            control_path: Some("/tmp/%r@%h:%p".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.expanded_control_path(),
+            Some("/tmp/me@host:22".into())
+        );
+        assert!(params
+            .command::<&str>(&[])
+            .contains(&"-oControlPath=/tmp/%r@%h:%p".into()));
+
+        // Same host and user, different options.
+        let other = SshParams {
+            force_tty: true,
+            ..params.clone()
+        };
+        assert!(params.shares_control_master(&other));
+
+        // Different port.
+        let other = SshParams {
+            address: Address::new("host", 2222),
+            ..params.clone()
+        };
+        assert!(!params.shares_control_master(&other));
+
+        // The hash token distinguishes hosts too.
+        let params = SshParams {
+            control_path: Some("/tmp/%C".into()),
+            ..params
+        };
+        let other = SshParams {
+            address: Address::from_host("other"),
+            ..params.clone()
+        };
+        assert!(params.shares_control_master(&params.clone()));
+        assert!(!params.shares_control_master(&other));
+
+        // A "none" path never shares.
+        let params = SshParams {
+            control_path: Some("none".into()),
+            ..params
+        };
+        assert!(!params.shares_control_master(&params.clone()));
+    }
+
+    #[test]
+    fn test_command_log_level() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec!["ssh", "-oBatchMode=yes", "host"]
+        );
+
+        let params = SshParams {
+            log_level: Some(LogLevel::Quiet),
+            ..params
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec!["ssh", "-oBatchMode=yes", "-oLogLevel=QUIET", "host"]
+        );
+
+        let params = SshParams {
+            log_level: Some(LogLevel::Debug2),
+            ..params
+        };
+        assert!(params
+            .command::<&str>(&[])
+            .contains(&"-oLogLevel=DEBUG2".into()));
+        assert_tokens(
+            &LogLevel::Debug2,
+            &[Token::UnitVariant {
+                name: "LogLevel",
+                variant: "DEBUG2",
+            }],
+        );
+    }
+
+    #[test]
+    fn test_command_config_file() {
+        let params = SshParams {
+            address: Address::new("host", 2222),
+            strict_host_key_checking: false,
+            config_file: Some("/dev/null".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command(&["true"]),
+            vec![
+                "ssh",
+                "-F",
+                "/dev/null",
+                "-oStrictHostKeyChecking=no",
+                "-oUserKnownHostsFile=/dev/null",
+                "-oBatchMode=yes",
+                "-p",
+                "2222",
+                "host",
+                "true"
+            ]
+        );
+
+        let params = SshParams {
+            config_file: None,
+            ..params
+        };
+        assert!(!params.command(&["true"]).contains(&"-F".into()));
+    }
+
+    #[test]
+    fn test_key_only() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ..SshParams::key_only()
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec![
+                "ssh",
+                "-oBatchMode=yes",
+                "-oPasswordAuthentication=no",
+                "-oKbdInteractiveAuthentication=no",
+                "-oPreferredAuthentications=publickey",
+                "host"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_command_authentication() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            batch_mode: false,
+            ..Default::default()
+        };
+        assert_eq!(params.command::<&str>(&[]), vec!["ssh", "host"]);
+
+        // Publickey-only, set field by field.
+        let params = SshParams {
+            password_authentication: Some(false),
+            preferred_authentications: vec!["publickey".to_string()],
+            ..params
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec![
+                "ssh",
+                "-oPasswordAuthentication=no",
+                "-oPreferredAuthentications=publickey",
+                "host"
+            ]
+        );
+
+        let params = SshParams {
+            password_authentication: None,
+            preferred_authentications: vec![
+                "publickey".to_string(),
+                "password".to_string(),
+            ],
+            number_of_password_prompts: Some(1),
+            ..params
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec![
+                "ssh",
+                "-oPreferredAuthentications=publickey,password",
+                "-oNumberOfPasswordPrompts=1",
+                "host"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ..SshParams::keep_alive()
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec![
+                "ssh",
+                "-oBatchMode=yes",
+                "-oServerAliveInterval=15",
+                "-oServerAliveCountMax=3",
+                "-oTCPKeepAlive=yes",
+                "host"
+            ]
+        );
+
+        let params = SshParams {
+            server_alive_interval: Some(Duration::from_secs(60)),
+            ..params
+        };
+        assert!(params
+            .command::<&str>(&[])
+            .contains(&"-oServerAliveInterval=60".into()));
+
+        // Sub-second durations round up rather than down to zero.
+        let params = SshParams {
+            server_alive_interval: Some(Duration::from_millis(500)),
+            tcp_keep_alive: None,
+            server_alive_count_max: None,
+            connect_timeout: Some(Duration::from_millis(900)),
+            ..params
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec![
+                "ssh",
+                "-oBatchMode=yes",
+                "-oServerAliveInterval=1",
+                "-oConnectTimeout=1",
+                "host"
+            ]
+        );
+        assert!(params
+            .to_ssh_config("host")
+            .unwrap()
+            .contains("ServerAliveInterval 1\n"));
+        let params = SshParams {
+            server_alive_interval: Some(Duration::from_millis(1500)),
+            ..params
+        };
+        assert!(params
+            .command::<&str>(&[])
+            .contains(&"-oServerAliveInterval=2".into()));
+    }
+
+    #[test]
+    fn test_sshpass_command() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.sshpass_command("SSHPASS", &["true"]),
+            vec!["sshpass", "-e", "ssh", "host", "true"]
+        );
+        assert_eq!(
+            params.sshpass_command("MY_PASSWORD", &["true"]),
+            vec!["sshpass", "-eMY_PASSWORD", "ssh", "host", "true"]
+        );
+    }
+
+    #[test]
+    fn test_sudo_command() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ..Default::default()
+        };
+        let cmd = params.sudo_command(&["ls", "/root dir"], None);
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            vec!["-oBatchMode=yes", "-t", "host", "sudo -- ls '/root dir'"]
+        );
+
+        let cmd = params.sudo_command(&["whoami"], Some("admin"));
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            vec!["-oBatchMode=yes", "-t", "host", "sudo -u admin -- whoami"]
+        );
+    }
+
+    #[test]
+    fn test_mosh_command() {
+        let params = SshParams {
+            address: Address::new("host", 2222),
+            user: Some("me".to_string()),
+            identity: Some("/my key".into()),
+            batch_mode: false,
+            force_tty: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            params.mosh_command(&["tmux", "attach"]),
+            vec![
+                "mosh",
+                "--ssh=ssh -i '/my key' -p 2222",
+                "me@host",
+                "--",
+                "tmux",
+                "attach"
+            ]
+        );
+        assert_eq!(
+            params.mosh_command::<&str>(&[]),
+            vec!["mosh", "--ssh=ssh -i '/my key' -p 2222", "me@host"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pipe_file_command() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir()
+            .join(format!("nbssh-test-pipe-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Use a stub in place of ssh that copies stdin to stdout.
+        let stub = dir.join("ssh");
+        std::fs::write(&stub, "#!/bin/sh\ncat\n").unwrap();
+        std::fs::set_permissions(&stub, std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+        let local = dir.join("upload");
+        std::fs::write(&local, "file contents").unwrap();
+
+        let params = SshParams {
+            address: Address::from_host("host"),
+            program: stub,
+            ..Default::default()
+        };
+        let mut cmd = params
+            .pipe_file_command(&local, &["sh", "-c", "cat > /dest"])
+            .unwrap();
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            vec!["-oBatchMode=yes", "host", "sh", "-c", "cat > /dest"]
+        );
+        let output = cmd.output().unwrap();
+        assert_eq!(output.stdout, b"file contents");
+
+        assert!(params
+            .pipe_file_command(&dir.join("missing"), &["true"])
+            .is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unbuffered_command() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ..Default::default()
+        };
+        let cmd = params.unbuffered_command(&["tail", "-f", "my log"]);
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            vec!["-oBatchMode=yes", "host", "stdbuf -oL -eL tail -f 'my log'"]
+        );
+    }
+
+    #[test]
+    fn test_connection_flags() {
+        let params = SshParams {
+            address: Address::new("host", 2222),
+            user: Some("me".to_string()),
+            identity: Some("/key".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.connection_flags(),
+            vec!["-oBatchMode=yes", "-i", "/key", "-p", "2222"]
+        );
+
+        let params = SshParams {
+            address: Address::from_host("host"),
+            batch_mode: false,
+            ..Default::default()
+        };
+        assert!(params.connection_flags().is_empty());
+
+        // Session mode flags and forwards are left out.
+        let params = SshParams {
+            address: Address::from_host("host"),
+            force_tty: true,
+            no_remote_command: true,
+            forwards: vec![Forward::local("8080", "localhost:80")],
+            gateway_ports: Some(GatewayPorts::Yes),
+            ..Default::default()
+        };
+        assert_eq!(params.connection_flags(), vec!["-oBatchMode=yes"]);
+    }
+
+    #[test]
+    fn test_git_ssh_command() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            identity: Some("/home/me/my key".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.git_ssh_command(),
+            "ssh -oBatchMode=yes -i '/home/me/my key'"
+        );
+
+        // A tunnel profile must not turn git's connection into a
+        // tunnel.
+        let params = SshParams {
+            force_tty: true,
+            no_remote_command: true,
+            forwards: vec![Forward::local("8080", "localhost:80")],
+            ..params
+        };
+        assert_eq!(
+            params.git_ssh_command(),
+            "ssh -oBatchMode=yes -i '/home/me/my key'"
+        );
+    }
+
+    #[test]
+    fn test_fan_out() {
+        let params = SshParams {
+            user: Some("me".to_string()),
+            batch_mode: false,
+            ..Default::default()
+        };
+        let hosts = [
+            Address::from_host("a"),
+            Address::new("b", 2222),
+            Address::from_host("::1"),
+        ];
+        assert_eq!(
+            params.fan_out(&hosts, &["uptime"]),
+            vec![
+                vec!["ssh", "me@a", "uptime"],
+                vec!["ssh", "-p", "2222", "me@b", "uptime"],
+                vec!["ssh", "me@::1", "uptime"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_commands_from_host_file() {
+        let path = std::env::temp_dir()
+            .join(format!("nbssh-test-hosts-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# Web servers\nweb1\nadmin@web2:2222\n\n  web3  \n",
+        )
+        .unwrap();
+
+        let params = SshParams {
+            user: Some("me".to_string()),
+            batch_mode: false,
+            ..Default::default()
+        };
+        let commands =
+            params.commands_from_host_file(&path, &["uptime"]).unwrap();
+        let command = |args: &[&str]| -> Vec<OsString> {
+            args.iter().map(OsString::from).collect()
+        };
+        assert_eq!(
+            commands,
+            vec![
+                (
+                    Address::from_host("web1"),
+                    command(&["ssh", "me@web1", "uptime"])
+                ),
+                (
+                    Address::new("web2", 2222),
+                    command(&["ssh", "-p", "2222", "admin@web2", "uptime"])
+                ),
+                (
+                    Address::from_host("web3"),
+                    command(&["ssh", "me@web3", "uptime"])
+                ),
+            ]
+        );
+
+        std::fs::write(&path, "web1\n@web2\n").unwrap();
+        let err = params
+            .commands_from_host_file(&path, &["uptime"])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_command_str() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            batch_mode: false,
+            ..Default::default()
+        };
+        assert_eq!(params.command_str("a b c"), vec!["ssh", "host", "a b c"]);
+        assert_eq!(
+            params.command(&["a", "b", "c"]),
+            vec!["ssh", "host", "a", "b", "c"]
+        );
+    }
+
+    #[test]
+    fn test_command_and_resolved() {
+        let params = SshParams {
+            address: Address::new("localhost", 2222),
+            batch_mode: false,
+            ..Default::default()
+        };
+        let (command, resolved) =
+            params.command_and_resolved(&["true"]).unwrap();
+        assert_eq!(command, params.command(&["true"]));
+        assert!(resolved.ip().is_loopback());
+        assert_eq!(resolved.port(), 2222);
+    }
+
+    #[test]
+    fn test_command_with_policy() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            batch_mode: false,
+            ..Default::default()
+        };
+        let args = ["echo", "a b"];
+        assert_eq!(
+            params.command_with_policy(&args, QuotingPolicy::Raw),
+            vec!["ssh", "host", "echo", "a b"]
+        );
+        assert_eq!(
+            params.command_with_policy(&args, QuotingPolicy::EscapeEach),
+            vec!["ssh", "host", "echo", "'a b'"]
+        );
+        assert_eq!(
+            params.command_with_policy(&args, QuotingPolicy::SingleString),
+            vec!["ssh", "host", "echo a b"]
+        );
+    }
+
+    #[test]
+    fn test_command_annotated() {
+        let params = SshParams {
+            address: Address::new("host", 2222),
+            identity: Some("/key".into()),
+            ..Default::default()
+        };
+        let annotated = params.command_annotated(&["uptime"]);
+        assert_eq!(
+            annotated,
+            vec![
+                ("ssh".into(), ArgSource::Program),
+                ("-oBatchMode=yes".into(), ArgSource::BatchMode),
+                ("-i".into(), ArgSource::Identity),
+                ("/key".into(), ArgSource::Identity),
+                ("-p".into(), ArgSource::Port),
+                ("2222".into(), ArgSource::Port),
+                ("host".into(), ArgSource::Target),
+                ("uptime".into(), ArgSource::RemoteArg),
+            ]
+        );
+        assert_eq!(
+            annotated
+                .into_iter()
+                .map(|(arg, _)| arg)
+                .collect::<Vec<_>>(),
+            params.command(&["uptime"])
+        );
+    }
+
+    #[test]
+    fn test_gateway_ports() {
+        let mut params = SshParams {
+            address: Address::from_host("host"),
+            batch_mode: false,
+            forwards: vec![Forward::remote("8080", "localhost:80")],
+            ..Default::default()
+        };
+        for (gateway_ports, arg) in [
+            (GatewayPorts::No, "-oGatewayPorts=no"),
+            (GatewayPorts::Yes, "-oGatewayPorts=yes"),
+            (
+                GatewayPorts::ClientSpecified,
+                "-oGatewayPorts=clientspecified",
+            ),
+        ]
+        .iter()
+        {
+            params.gateway_ports = Some(*gateway_ports);
+            assert_eq!(
+                params.command::<&str>(&[]),
+                vec!["ssh", "-R", "8080:localhost:80", arg, "host"]
+            );
+        }
+        assert_eq!(params.without_forwards().gateway_ports, None);
+        assert_tokens(
+            &GatewayPorts::ClientSpecified,
+            &[Token::UnitVariant {
+                name: "GatewayPorts",
+                variant: "clientspecified",
+            }],
+        );
+    }
+
+    #[test]
+    fn test_ignore_unknown() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ignore_unknown: vec!["SetEnv".to_string(), "Tag".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command(&["true"]),
+            vec![
+                "ssh",
+                "-oIgnoreUnknown=SetEnv,Tag",
+                "-oBatchMode=yes",
+                "host",
+                "true"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_address_family() {
+        let mut params = SshParams {
+            address: Address::new("host", 2222),
+            batch_mode: false,
+            ..Default::default()
+        };
+        assert_eq!(params.address_family, AddressFamily::Any);
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec!["ssh", "-p", "2222", "host"]
+        );
+
+        params.address_family = AddressFamily::Inet;
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec!["ssh", "-p", "2222", "-4", "host"]
+        );
+
+        params.address_family = AddressFamily::Inet6;
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec!["ssh", "-p", "2222", "-6", "host"]
+        );
+    }
+
+    #[test]
+    fn test_known_hosts_policy() {
+        let mut params = SshParams {
+            address: Address::from_host("host"),
+            batch_mode: false,
+            known_hosts_files: vec!["/known".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec!["ssh", "-oUserKnownHostsFile=/known", "host"]
+        );
+
+        params.known_hosts_policy = KnownHostsPolicy::Ignore;
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec![
+                "ssh",
+                "-oStrictHostKeyChecking=no",
+                "-oUserKnownHostsFile=/dev/null",
+                "host"
+            ]
+        );
+
+        params.known_hosts_policy =
+            KnownHostsPolicy::SessionTemp("/tmp/session hosts".into());
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec![
+                "ssh",
+                "-oStrictHostKeyChecking=accept-new",
+                "-oUserKnownHostsFile=\"/tmp/session hosts\"",
+                "host"
+            ]
+        );
+
+        // strict_host_key_checking takes precedence.
+        params.strict_host_key_checking = false;
+        assert!(params
+            .command::<&str>(&[])
+            .contains(&"-oStrictHostKeyChecking=no".into()));
+    }
+
+    #[cfg(feature = "tempfile")]
+    #[test]
+    fn test_session_known_hosts() {
+        let session = SessionKnownHosts::new().unwrap();
+        let path = session.path().to_path_buf();
+        assert!(path.exists());
+
+        let params = SshParams {
+            address: Address::from_host("host"),
+            known_hosts_policy: session.policy(),
+            ..Default::default()
+        };
+        let arg = format!("-oUserKnownHostsFile={}", path.display());
+        assert!(params.command::<&str>(&[]).contains(&arg.into()));
+
+        drop(session);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_tag() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            batch_mode: false,
+            tag: Some("deploy".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec!["ssh", "-oTag=deploy", "host"]
+        );
+        assert_eq!(
+            params.validate_for_version(&SshVersion::new(9, 3)),
+            vec![VersionWarning {
+                option: "Tag",
+                required: SshVersion::new(9, 4)
+            }]
+        );
+        assert!(params
+            .validate_for_version(&SshVersion::new(9, 4))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_command_single_port() {
+        let params = SshParams {
+            address: Address::new("host", 2222),
+            identity: Some("/key".into()),
+            forwards: vec![Forward::local("8080", "localhost:80")],
+            control_path: Some("/tmp/%h:%p".into()),
+            ..SshParams::robust_connect(10)
+        };
+        let command = params.command(&["true"]);
+        assert_eq!(command.iter().filter(|arg| *arg == "-p").count(), 1);
+        let index = command.iter().position(|arg| arg == "-p").unwrap();
+        assert_eq!(command[index + 1], "2222");
+    }
+
+    #[test]
+    fn test_command_minimal() {
+        let params = SshParams {
+            address: Address::from_host("myalias"),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command_minimal(&["uptime"]),
+            vec!["ssh", "myalias", "uptime"]
+        );
+
+        // Explicitly-set fields are still emitted.
+        let params = SshParams {
+            address: Address::new("myalias", 2222),
+            user: Some("me".to_string()),
+            ..params
+        };
+        assert_eq!(
+            params.command_minimal(&["uptime"]),
+            vec!["ssh", "-p", "2222", "me@myalias", "uptime"]
+        );
+
+        // Options equal to ssh's defaults are left out.
+        let params = SshParams {
+            address: Address::new("host", 22),
+            log_level: Some(LogLevel::Info),
+            tcp_keep_alive: Some(true),
+            connection_attempts: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command(&["uptime"]),
+            vec![
+                "ssh",
+                "-oBatchMode=yes",
+                "-p",
+                "22",
+                "-oLogLevel=INFO",
+                "-oTCPKeepAlive=yes",
+                "-oConnectionAttempts=1",
+                "host",
+                "uptime"
+            ]
+        );
+        assert_eq!(
+            params.command_minimal(&["uptime"]),
+            vec!["ssh", "host", "uptime"]
+        );
+    }
+
+    #[test]
+    fn test_command_connection_attempts() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec!["ssh", "-oBatchMode=yes", "host"]
+        );
+
+        let params = SshParams {
+            connection_attempts: Some(5),
+            ..params
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec!["ssh", "-oBatchMode=yes", "-oConnectionAttempts=5", "host"]
+        );
+        assert_eq!(params.validate(&["true"]), Ok(()));
+
+        let params = SshParams {
+            connection_attempts: Some(0),
+            ..params
+        };
+        assert_eq!(
+            params.validate(&["true"]),
+            Err(SshParamsError::ZeroConnectionAttempts)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_to_command_with_arg0() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            program: "/opt/bin/ssh-wrapper".into(),
+            ..Default::default()
+        };
+        let cmd = params.to_command_with_arg0(&["true"], "ssh".as_ref());
+        assert_eq!(cmd.get_program(), "/opt/bin/ssh-wrapper");
+        assert_eq!(
+            format!("{:?}", cmd),
+            r#"["/opt/bin/ssh-wrapper"] "ssh" "-oBatchMode=yes" "host" "true""#
+        );
+    }
+
+    #[test]
+    fn test_command_algorithms() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ciphers: Some("aes256-gcm@openssh.com".to_string()),
+            macs: Some("-hmac-sha1".to_string()),
+            kex_algorithms: Some("+diffie-hellman-group14-sha1".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec![
+                "ssh",
+                "-oCiphers=aes256-gcm@openssh.com",
+                "-oMACs=-hmac-sha1",
+                "-oKexAlgorithms=+diffie-hellman-group14-sha1",
+                "-oBatchMode=yes",
+                "host"
+            ]
+        );
+
+        // "none" is passed through as-is for benchmarking.
+        let params = SshParams {
+            ciphers: Some("none".to_string()),
+            macs: Some("none".to_string()),
+            kex_algorithms: None,
+            ..params
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec![
+                "ssh",
+                "-oCiphers=none",
+                "-oMACs=none",
+                "-oBatchMode=yes",
+                "host"
+            ]
+        );
+        assert_eq!(params.validate::<&str>(&[]), Ok(()));
+    }
+
+    #[test]
+    fn test_command_env() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            send_env: vec!["LANG".to_string(), "LC_*".to_string()],
+            set_env: vec![
+                ("FOO".to_string(), "1".to_string()),
+                ("BAR".to_string(), "a b".to_string()),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec![
+                "ssh",
+                "-oBatchMode=yes",
+                "-oSendEnv=LANG",
+                "-oSendEnv=LC_*",
+                "-oSetEnv=FOO=1",
+                "-oSetEnv=BAR=\"a b\"",
+                "host"
+            ]
+        );
+        assert_eq!(params.validate(&["true"]), Ok(()));
+
+        let params = SshParams {
+            send_env: vec!["FOO=1".to_string()],
+            ..params
+        };
+        assert_eq!(
+            params.validate(&["true"]),
+            Err(SshParamsError::InvalidSendEnv("FOO=1".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_multi_hop() {
+        let hop1 = SshParams {
+            address: Address::from_host("hop1"),
+            identity: Some("/key1".into()),
+            ..Default::default()
+        };
+        let hop2 = SshParams {
+            address: Address::new("hop2", 2222),
+            ..Default::default()
+        };
+        let dest = SshParams {
+            address: Address::from_host("dest"),
+            ..Default::default()
+        };
+
+        let cmd = dest
+            .multi_hop(std::slice::from_ref(&hop1), &["true"])
+            .unwrap();
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            vec![
+                "-oBatchMode=yes",
+                "-oProxyCommand=ssh -oBatchMode=yes -i /key1 -W %h:%p hop1",
+                "dest",
+                "true"
+            ]
+        );
+
+        let cmd = dest.multi_hop(&[hop1, hop2], &["true"]).unwrap();
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            vec![
+                "-oBatchMode=yes",
+                "-oProxyCommand=ssh -oBatchMode=yes -p 2222 \
+                 '-oProxyCommand=ssh -oBatchMode=yes -i /key1 -W %%h:%%p hop1' \
+                 -W %h:%p hop2",
+                "dest",
+                "true"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_proxy_command() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            batch_mode: false,
+            proxy_command: Some("cloudflared access ssh --hostname %h".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command(&["true"]),
+            vec![
+                "ssh",
+                "-oProxyCommand=cloudflared access ssh --hostname %h",
+                "host",
+                "true"
+            ]
+        );
+
+        // A ProxyCommand conflicts with jump hosts.
+        let hop = SshParams {
+            address: Address::from_host("hop"),
+            batch_mode: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            params
+                .multi_hop(std::slice::from_ref(&hop), &["true"])
+                .unwrap_err(),
+            SshParamsError::ProxyCommandWithHops
+        );
+        assert_eq!(
+            hop.multi_hop(&[hop.clone(), params.clone()], &["true"])
+                .unwrap_err(),
+            SshParamsError::ProxyCommandWithHops
+        );
+
+        // The first hop is connected to directly, so it may use one.
+        let cmd = hop.multi_hop(&[params], &["true"]).unwrap();
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            vec![
+                "-oProxyCommand=ssh \
+                 '-oProxyCommand=cloudflared access ssh --hostname %%h' \
+                 -W %h:%p host",
+                "hop",
+                "true"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multi_hop_netcat() {
+        let hop = SshParams {
+            address: Address::from_host("hop"),
+            batch_mode: false,
+            jump_via_netcat: Some("nc".to_string()),
+            ..Default::default()
+        };
+        let dest = SshParams {
+            address: Address::from_host("dest"),
+            batch_mode: false,
+            ..Default::default()
+        };
+        let cmd = dest
+            .multi_hop(std::slice::from_ref(&hop), &["true"])
+            .unwrap();
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            vec!["-oProxyCommand=ssh hop nc %h %p", "dest", "true"]
+        );
+
+        let hop = SshParams {
+            jump_via_netcat: Some("ncat".to_string()),
+            ..hop
+        };
+        let cmd = dest.multi_hop(&[hop], &["true"]).unwrap();
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            vec!["-oProxyCommand=ssh hop ncat %h %p", "dest", "true"]
+        );
+    }
+
+    #[test]
+    fn test_ssh_version_parse() {
+        assert_eq!(
+            SshVersion::parse(
+                "OpenSSH_9.6p1 Ubuntu-3ubuntu13, OpenSSL 3.0.13 30 Jan 2024\n"
+            ),
+            Some(SshVersion::new(9, 6))
+        );
+        assert_eq!(
+            SshVersion::parse("OpenSSH_for_Windows_8.1p1, LibreSSL 3.0.2"),
+            Some(SshVersion::new(8, 1))
+        );
+        assert_eq!(
+            SshVersion::parse("OpenSSH_10.0, OpenSSL 3.5.0"),
+            Some(SshVersion::new(10, 0))
+        );
+        assert_eq!(SshVersion::parse("Dropbear v2022.83"), None);
+
+        assert!(SshVersion::new(9, 6) > SshVersion::new(9, 5));
+        assert!(SshVersion::new(10, 0) > SshVersion::new(9, 9));
+        assert_eq!(SshVersion::new(9, 6).to_string(), "9.6");
+    }
+
+    #[test]
+    fn test_params_parse_target() {
+        assert_eq!(
+            SshParams::parse_target("me@host"),
+            Ok((Address::from_host("host"), Some("me".to_string())))
+        );
+        assert_eq!(
+            SshParams::parse_target("host:22"),
+            Ok((Address::new("host", 22), None))
+        );
+        assert_eq!(
+            SshParams::parse_target("me@[::1]:2222"),
+            Ok((Address::new("::1", 2222), Some("me".to_string())))
+        );
+        assert_eq!(
+            SshParams::parse_target("[::1]:2222"),
+            Ok((Address::new("::1", 2222), None))
+        );
+        assert_eq!(
+            SshParams::parse_target("me@host:x"),
+            Err(AddressError::InvalidPort)
+        );
+    }
+
+    #[test]
+    fn test_validate_for_version() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            set_env: vec![("FOO".to_string(), "1".to_string())],
+            ..Default::default()
+        };
+        let warnings = params.validate_for_version(&SshVersion::new(7, 4));
+        assert_eq!(
+            warnings,
+            vec![VersionWarning {
+                option: "SetEnv",
+                required: SshVersion::new(7, 8),
+            }]
+        );
+        assert_eq!(
+            warnings[0].to_string(),
+            "option SetEnv requires OpenSSH 7.8 or newer"
+        );
+
+        assert!(params
+            .validate_for_version(&SshVersion::new(9, 6))
+            .is_empty());
+
+        let params = SshParams {
+            address: Address::from_host("host"),
+            known_hosts_policy: KnownHostsPolicy::SessionTemp(
+                "/tmp/known_hosts".into(),
+            ),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.validate_for_version(&SshVersion::new(7, 4)),
+            vec![VersionWarning {
+                option: "StrictHostKeyChecking=accept-new",
+                required: SshVersion::new(7, 6),
+            }]
+        );
+        assert!(params
+            .validate_for_version(&SshVersion::new(7, 6))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_command_batch_mode() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            batch_mode: false,
+            ..Default::default()
+        };
+        assert_eq!(params.command(&["true"]), vec!["ssh", "host", "true"]);
+    }
+
+    #[test]
+    fn test_extend_command() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ..Default::default()
+        };
+        let mut cmd = Command::new("timeout");
+        cmd.arg("30");
+        params.extend_command(&mut cmd, &["uptime"]);
+        assert_eq!(cmd.get_program(), "timeout");
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            vec!["30", "ssh", "-oBatchMode=yes", "host", "uptime"]
+        );
+    }
+
+    #[test]
+    fn test_robust_connect() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ..SshParams::robust_connect(10)
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec![
+                "ssh",
+                "-oBatchMode=yes",
+                "-oServerAliveInterval=10",
+                "-oServerAliveCountMax=3",
+                "-oConnectionAttempts=3",
+                "-oConnectTimeout=10",
+                "host"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_without_forwards() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            user: Some("me".to_string()),
+            forwards: vec![
+                Forward::local("8080", "localhost:80"),
+                Forward::dynamic("1080"),
+            ],
+            no_remote_command: true,
+            ..Default::default()
+        };
+        let stripped = params.without_forwards();
+        assert!(stripped.forwards.is_empty());
+        assert_eq!(
+            stripped,
+            SshParams {
+                forwards: Vec::new(),
+                no_remote_command: false,
+                ..params
+            }
+        );
+    }
+
+    #[test]
+    fn test_command_control_master() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            control_master: Some(ControlMaster::Auto),
+            control_path: Some("~/.ssh/cm-%r@%h:%p".into()),
+            control_persist: Some("10m".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command(&["true"]),
+            vec![
+                "ssh",
+                "-oBatchMode=yes",
+                "-oControlMaster=auto",
+                "-oControlPath=~/.ssh/cm-%r@%h:%p",
+                "-oControlPersist=10m",
+                "host",
+                "true"
+            ]
+        );
+        assert_eq!(ControlMaster::Autoask.to_string(), "autoask");
+    }
+
+    #[test]
+    fn test_command_into() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        params.command_into(&mut buf, &["echo", "one"]);
+        assert_eq!(buf, params.command(&["echo", "one"]));
+
+        let params = SshParams {
+            address: Address::from_host("other"),
+            ..params
+        };
+        params.command_into(&mut buf, &["true"]);
+        assert_eq!(buf, vec!["ssh", "-oBatchMode=yes", "other", "true"]);
+    }
+
+    #[test]
+    fn test_command_with_remote_env() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ..Default::default()
+        };
+        let cmd = params
+            .command_with_remote_env(
+                &[("FOO", "bar baz"), ("N", "1")],
+                &["printenv", "FOO"],
+            )
+            .unwrap();
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            vec![
+                "-oBatchMode=yes",
+                "host",
+                "env FOO='bar baz' N=1 printenv FOO"
+            ]
+        );
+
+        // Keys are not quoted, so they must be plain names.
+        assert_eq!(
+            params
+                .command_with_remote_env(&[("A;id;B", "x")], &["true"])
+                .unwrap_err(),
+            SshParamsError::InvalidRemoteEnv("A;id;B".to_string())
+        );
+        assert_eq!(
+            params
+                .command_with_remote_env::<&str>(&[("FOO", "x")], &[])
+                .unwrap_err(),
+            SshParamsError::MissingRemoteCommand
+        );
+    }
+
+    #[test]
+    fn test_command_args() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ..Default::default()
+        };
+        let path = PathBuf::from("/var/log");
+        let args = SshArgs::new()
+            .push_str("ls")
+            .push_os("-l".as_ref())
+            .push_path(&path);
+        assert_eq!(
+            params.command_args(args),
+            vec!["ssh", "-oBatchMode=yes", "host", "ls", "-l", "/var/log"]
+        );
+    }
+
+    #[test]
+    fn test_command_login_shell() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            login_shell: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command(&["echo", "'a b'", "\"it's\""]),
+            vec![
+                "ssh",
+                "-oBatchMode=yes",
+                "host",
+                "sh -lc 'echo '\\''a b'\\'' \"it'\\''s\"'"
+            ]
+        );
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec!["ssh", "-oBatchMode=yes", "host"]
+        );
+    }
+
+    #[test]
+    fn test_try_set_env() {
+        let mut params = SshParams {
+            address: Address::from_host("host"),
+            ..Default::default()
+        };
+        assert_eq!(params.try_set_env("MY_VAR", "1"), Ok(()));
+        assert_eq!(params.try_set_env("_x2", "2"), Ok(()));
+        assert_eq!(
+            params.try_set_env("MY-VAR", "3"),
+            Err(SshParamsError::InvalidSetEnv("MY-VAR".to_string()))
+        );
+        assert_eq!(
+            params.try_set_env("2X", "4"),
+            Err(SshParamsError::InvalidSetEnv("2X".to_string()))
+        );
+        assert_eq!(
+            params.set_env,
+            vec![
+                ("MY_VAR".to_string(), "1".to_string()),
+                ("_x2".to_string(), "2".to_string())
+            ]
+        );
+        assert_eq!(params.validate(&["true"]), Ok(()));
+
+        params.set_env.push(("MY-VAR".to_string(), "3".to_string()));
+        assert_eq!(
+            params.validate(&["true"]),
+            Err(SshParamsError::InvalidSetEnv("MY-VAR".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_tcl_quote() {
+        assert_eq!(tcl_quote("abc"), "abc");
+        assert_eq!(tcl_quote(""), "{}");
+        assert_eq!(tcl_quote("a b"), "{a b}");
+        assert_eq!(tcl_quote("$x"), "{$x}");
+        assert_eq!(tcl_quote("a}b"), "a\\}b");
+        assert_eq!(tcl_quote("a b\\"), "a\\ b\\\\");
+    }
+
+    #[test]
+    fn test_expect_spawn_line() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            user: Some("admin".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.expect_spawn_line(&["show", "running config"]),
+            "spawn ssh -oBatchMode=yes admin@host show {running config}"
+        );
+    }
+
+    #[test]
+    fn test_command_bind_address() {
+        let params = SshParams {
+            address: Address::new("host", 2222),
+            bind_address: Some("192.168.1.10".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command(&["true"]),
+            vec![
+                "ssh",
+                "-oBatchMode=yes",
+                "-p",
+                "2222",
+                "-b",
+                "192.168.1.10",
+                "host",
+                "true"
+            ]
+        );
+
+        let params = SshParams {
+            bind_address: None,
+            ..params
+        };
+        assert!(!params.command(&["true"]).contains(&"-b".into()));
+    }
+
+    #[test]
+    fn test_command_known_hosts_files() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            known_hosts_files: vec![
+                "/etc/ssh/pinned_hosts".into(),
+                "/home/me/my hosts".into(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec![
+                "ssh",
+                "-oUserKnownHostsFile=/etc/ssh/pinned_hosts \"/home/me/my hosts\"",
+                "-oBatchMode=yes",
+                "host"
+            ]
+        );
+
+        let params = SshParams {
+            strict_host_key_checking: false,
+            ..params
+        };
+        assert_eq!(
+            params.command::<&str>(&[]),
+            vec![
+                "ssh",
+                "-oStrictHostKeyChecking=no",
+                "-oUserKnownHostsFile=/dev/null",
+                "-oBatchMode=yes",
+                "host"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_interpret_exit_code() {
+        assert_eq!(SshParams::interpret_exit_code(0), ExitKind::Success);
+        assert_eq!(SshParams::interpret_exit_code(255), ExitKind::SshError);
+        assert_eq!(
+            SshParams::interpret_exit_code(1),
+            ExitKind::RemoteFailure(1)
+        );
+    }
+
+    #[test]
+    fn test_command_no_remote_command() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            no_remote_command: true,
+            forwards: vec![Forward::local("8080", "localhost:80")],
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command(&["echo", "dropped"]),
+            vec![
+                "ssh",
+                "-oBatchMode=yes",
+                "-N",
+                "-L",
+                "8080:localhost:80",
+                "host"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remote_command() {
+        assert_eq!(RemoteCommand::new::<&str>("ls", &[]).as_str(), "ls");
+        assert_eq!(
+            RemoteCommand::new("rm", &["-rf", "/tmp/my dir"]).as_str(),
+            "rm -rf '/tmp/my dir'"
+        );
+        assert_eq!(
+            RemoteCommand::new("echo", &["", "it's", "*.txt", "$HOME"])
+                .as_str(),
+            "echo '' 'it'\\''s' '*.txt' '$HOME'"
+        );
+        assert_eq!(RemoteCommand::raw("ls *.txt").as_str(), "ls *.txt");
+
+        let params = SshParams {
+            address: Address::from_host("host"),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command(&[RemoteCommand::new("cat", &["a b"])]),
+            vec!["ssh", "-oBatchMode=yes", "host", "cat 'a b'"]
+        );
+    }
+
+    #[test]
+    fn test_copy_id_command() {
+        let params = SshParams {
+            address: Address::new("host", 2222),
+            user: Some("me".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.copy_id_command(Path::new("/key.pub")),
+            vec!["ssh-copy-id", "-i", "/key.pub", "-p", "2222", "me@host"]
+        );
+
+        let params = SshParams {
+            address: Address::from_host("::1"),
+            user: None,
+            strict_host_key_checking: false,
+            ..params
+        };
+        assert_eq!(
+            params.copy_id_command(Path::new("/key.pub")),
+            vec![
+                "ssh-copy-id",
+                "-i",
+                "/key.pub",
+                "-oStrictHostKeyChecking=no",
+                "-oUserKnownHostsFile=/dev/null",
+                "::1"
+            ]
+        );
+
+        // Host keys are checked the same way as by `command`.
+        for params in [
+            SshParams {
+                strict_host_key_checking: true,
+                known_hosts_policy: KnownHostsPolicy::Ignore,
+                ..params.clone()
+            },
+            SshParams {
+                strict_host_key_checking: true,
+                known_hosts_policy: KnownHostsPolicy::SessionTemp(
+                    "/tmp/known_hosts".into(),
+                ),
+                ..params.clone()
+            },
+            SshParams {
+                strict_host_key_checking: true,
+                known_hosts_files: vec!["/etc/hosts_a".into()],
+                ..params.clone()
+            },
+        ] {
+            let copy_id = params.copy_id_command(Path::new("/key.pub"));
+            let command = params.command::<&str>(&[]);
+            let options: Vec<_> = command
+                .iter()
+                .filter(|arg| {
+                    let arg = arg.to_string_lossy();
+                    arg.starts_with("-oStrictHostKeyChecking=")
+                        || arg.starts_with("-oUserKnownHostsFile=")
+                })
+                .collect();
+            assert!(!options.is_empty());
+            assert_eq!(
+                copy_id[3..copy_id.len() - 1].iter().collect::<Vec<_>>(),
+                options
+            );
+        }
+    }
+
+    #[test]
+    fn test_params_hash() {
+        let a = SshParams {
+            address: Address::from_host("a"),
+            identity: Some("/key".into()),
+            ..Default::default()
+        };
+        let b = SshParams {
+            address: Address::from_host("b"),
+            ..a.clone()
+        };
+        let mut set = HashSet::new();
+        assert!(set.insert(a.clone()));
+        assert!(!set.insert(a.clone()));
+        assert!(set.insert(b.clone()));
+        assert!(set.insert(SshParams {
+            forwards: vec![Forward::dynamic("1080")],
+            ..b.clone()
+        }));
+        assert_eq!(set.len(), 3);
+        assert!(set.contains(&a));
+        assert!(set.contains(&b));
+    }
+
+    #[test]
+    fn test_command_string_redacted() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            user: Some("me".to_string()),
+            identity: Some("/home/me/.ssh/prod_key".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.command_string_redacted(&["sed", "-i", "s/a/b/", "f"]),
+            "ssh -oBatchMode=yes -i '/home/me/.ssh/***' me@host sed -i s/a/b/ f"
+        );
+    }
+
+    #[test]
+    fn test_to_ssh_config() {
+        let params = SshParams {
+            address: Address::from_host("example.com"),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.to_ssh_config("ex").unwrap(),
+            "Host ex\n    HostName example.com\n"
+        );
+
+        let params = SshParams {
+            address: Address::new("example.com", 2222),
+            user: Some("me".to_string()),
+            identity: Some("/home/me/.ssh/id_ed25519".into()),
+            strict_host_key_checking: false,
+            forwards: vec![Forward::local("8080", "localhost:80")],
+            set_env: vec![("GREETING".to_string(), "hi there".to_string())],
+            ..SshParams::keep_alive()
+        };
+        assert_eq!(
+            params.to_ssh_config("ex").unwrap(),
+            "\
+Host ex
+    HostName example.com
+    Port 2222
+    User me
+    IdentityFile /home/me/.ssh/id_ed25519
+    StrictHostKeyChecking no
+    UserKnownHostsFile /dev/null
+    LocalForward 8080 localhost:80
+    ServerAliveInterval 15
+    ServerAliveCountMax 3
+    TCPKeepAlive yes
+    SetEnv GREETING=\"hi there\"
+"
+        );
+
+        // Newlines would inject other directives.
+        assert_eq!(
+            params.to_ssh_config("x\nHost *\n  ProxyCommand evil"),
+            Err(ConfigError::InvalidValue {
+                keyword: "Host".to_string(),
+                value: "x\nHost *\n  ProxyCommand evil".to_string(),
+            })
+        );
+        let params = SshParams {
+            user: Some("me\nProxyCommand evil".to_string()),
+            ..params
+        };
+        assert_eq!(
+            params.to_ssh_config("ex"),
+            Err(ConfigError::InvalidValue {
+                keyword: "User".to_string(),
+                value: "\"me\nProxyCommand evil\"".to_string(),
+            })
+        );
+        let params = SshParams {
+            user: None,
+            tag: Some("t\r".to_string()),
+            ..params
+        };
+        assert!(params.to_ssh_config("ex").is_err());
+    }
+
+    #[test]
+    fn test_from_ssh_config() {
+        let params = SshParams::from_ssh_config(
+            "\
+Host ex
+    HostName example.com
+    Port 2222
+    User me
+    # Comment
+    IdentityFile /home/me/.ssh/id_ed25519
+    IdentityFile \"/home/me/my key\"
+    StrictHostKeyChecking no
+    ServerAliveInterval 15
+",
+        )
+        .unwrap();
+        assert_eq!(
+            params,
+            SshParams {
+                address: Address::new("example.com", 2222),
+                user: Some("me".to_string()),
+                identity: Some("/home/me/.ssh/id_ed25519".into()),
+                extra_identities: vec!["/home/me/my key".into()],
+                strict_host_key_checking: false,
+                ..Default::default()
+            }
+        );
+
+        // Keywords are case-insensitive and may use "=".
+        let params = SshParams::from_ssh_config(
+            "host ex\n  HOSTNAME=example.com\n  port = 22\n  uSeR me\n",
+        )
+        .unwrap();
+        assert_eq!(params.address, Address::new("example.com", 22));
+        assert_eq!(params.user.as_deref(), Some("me"));
+
+        // Host is used if there is no HostName.
+        let params = SshParams::from_ssh_config("Host example.com").unwrap();
+        assert_eq!(params.address, Address::from_host("example.com"));
+
+        assert_eq!(
+            SshParams::from_ssh_config("Host a\nPort x"),
+            Err(ConfigError::InvalidValue {
+                keyword: "Port".to_string(),
+                value: "x".to_string(),
+            })
+        );
+        assert_eq!(
+            SshParams::from_ssh_config("Host a\nHost b"),
+            Err(ConfigError::MultipleHosts)
+        );
+        assert_eq!(
+            SshParams::from_ssh_config("User me"),
+            Err(ConfigError::MissingHostName)
+        );
+        assert_eq!(
+            SshParams::from_ssh_config("Host"),
+            Err(ConfigError::MissingValue("Host".to_string()))
+        );
+
+        // The host must not be mistaken for an ssh option.
+        assert_eq!(
+            SshParams::from_ssh_config(
+                "Host x\nHostName -oProxyCommand=touch /tmp/pwn"
+            ),
+            Err(ConfigError::InvalidValue {
+                keyword: "HostName".to_string(),
+                value: "-oProxyCommand=touch /tmp/pwn".to_string(),
+            })
+        );
+        assert_eq!(
+            SshParams::from_ssh_config("Host -oProxyCommand=x"),
+            Err(ConfigError::InvalidValue {
+                keyword: "Host".to_string(),
+                value: "-oProxyCommand=x".to_string(),
+            })
+        );
+
+        // A Host line without a HostName must name a single host.
+        assert_eq!(
+            SshParams::from_ssh_config("Host a b"),
+            Err(ConfigError::InvalidValue {
+                keyword: "Host".to_string(),
+                value: "a b".to_string(),
+            })
+        );
+        assert_eq!(
+            SshParams::from_ssh_config("Host *"),
+            Err(ConfigError::InvalidValue {
+                keyword: "Host".to_string(),
+                value: "*".to_string(),
+            })
+        );
+        // Wildcards are fine when there is a HostName.
+        let params =
+            SshParams::from_ssh_config("Host *.ex\nHostName a.ex").unwrap();
+        assert_eq!(params.address, Address::from_host("a.ex"));
+    }
+
+    #[test]
+    fn test_askpass() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            askpass: Some("/usr/bin/ksshaskpass".into()),
+            ..Default::default()
+        };
+        assert_eq!(params.command(&["true"]), vec!["ssh", "host", "true"]);
+
+        let cmd = params.to_command(&["true"]);
+        let envs: Vec<_> = cmd.get_envs().collect();
+        assert_eq!(
+            envs,
+            vec![
+                (
+                    OsStr::new("SSH_ASKPASS"),
+                    Some(OsStr::new("/usr/bin/ksshaskpass"))
+                ),
+                (OsStr::new("SSH_ASKPASS_REQUIRE"), Some(OsStr::new("force"))),
+            ]
+        );
+
+        // Every builder that returns a Command sets the environment.
+        let hop = SshParams {
+            address: Address::from_host("jump"),
+            ..Default::default()
+        };
+        let mut timeout = Command::new("timeout");
+        timeout.arg("30");
+        params.extend_command(&mut timeout, &["true"]);
+        let mut commands = vec![
+            params.multi_hop(&[hop], &["true"]).unwrap(),
+            params
+                .command_with_remote_env(&[("A", "b")], &["true"])
+                .unwrap(),
+            timeout,
+        ];
+        #[cfg(target_os = "linux")]
+        commands.push(params.logged_command(&["true"], Path::new("/tmp/log")));
+        for cmd in &commands {
+            assert_eq!(cmd.get_envs().collect::<Vec<_>>(), envs);
+        }
+
+        let params = SshParams {
+            askpass: None,
+            ..params
+        };
+        assert_eq!(params.to_command(&["true"]).get_envs().count(), 0);
+    }
+
+    #[test]
+    fn test_command_password() {
+        let params = SshParams {
+            address: Address::from_host("host"),
+            password: Some("hunter2".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(params.command(&["true"]), vec!["ssh", "host", "true"]);
+        assert_eq!(
+            params.command_string_redacted(&["echo", "hunter2"]),
+            "ssh host echo '***'"
+        );
+
+        // Only whole remote arguments are redacted.
+        let params = SshParams {
+            password: Some("ssh".to_string()),
+            ..params
+        };
+        assert_eq!(
+            params.command_string_redacted(&["ls", "sshd", "ssh"]),
+            "ssh host ls sshd '***'"
+        );
+
+        // The password is not revealed by Debug or serialization.
+        let debug = format!("{:?}", params);
+        assert!(debug.contains(r#"password: Some("***")"#));
+        assert_ser_tokens(
+            &params,
+            &[
+                Token::Struct {
+                    name: "SshParams",
+                    len: 1,
+                },
+                Token::Str("address"),
+                Token::Str("host"),
+                Token::StructEnd,
+            ],
+        );
+    }
 }